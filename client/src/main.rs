@@ -1,78 +1,245 @@
-use bincode::serialize;
+use bincode::{deserialize, serialize};
 use lib::BlockData;
 use lib::Car;
+use lib::ChainParams;
+use lib::ChainState;
 use lib::Comm;
+use lib::ContractCall;
 use lib::Msg;
 use lib::RevPolish;
+use lib::{export_chain, import_chain};
+use lib::{BanEntry, PeerInfo, SourceStats, VehicleHistory, Vin};
+use lib::{ClusterKey, Endpoint, NetConfig, UdpTransport};
 use rand::Rng;
 use std::env;
-use std::net::UdpSocket;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 static NAMES: [&str; 10] = [
     "James", "Oliver", "Max", "Muller", "Bravo", "Fox", "Jimmy", "Jakub", "Willy", "Billy",
 ];
 
-/// Sends a `Msg` over a `UdpSocket` to the IP address `239.0.0.1` on port `9000`.
-///
-/// # Arguments
-///
-/// * `socket` - The `UdpSocket` to use for sending the message.
-/// * `msg` - The `Msg` to send over the `UdpSocket`.
-///
-/// # Example
-///
-/// ```
-/// let socket = UdpSocket::bind("0.0.0.0:9000").expect("Error binding socket");
-/// let msg = Msg::new("hello world");
-/// send_data(socket, msg);
-/// ```
-fn send_data(socket: UdpSocket, msg: Msg) {
-    println!(
-        "Broadcasted {} bytes",
-        socket
-            .send_to(
-                &serialize(&msg).expect("Error serializing"),
-                "239.0.0.1:9000"
-            )
-            .expect("Error sending message")
-    );
+/// Sends `msg` to the multicast group of `endpoint`.
+fn send_data(endpoint: &Endpoint, msg: Msg) {
+    endpoint.send_all(&msg).expect("Error sending message");
+    println!("Broadcasted {:?} message", msg.command);
+}
+
+/// Random VIN made of characters allowed in real ones.
+fn random_vin(rng: &mut impl Rng) -> Vin {
+    const CHARS: &[u8] = b"ABCDEFGHJKLMNPRSTUVWXYZ0123456789";
+    let vin: String = (0..17)
+        .map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char)
+        .collect();
+    Vin::parse(&vin).unwrap()
+}
+
+/// Waits up to five seconds for the first message whose command matches
+/// `expected` and returns it with the address of the node that sent it.
+fn wait_for_reply(endpoint: &Endpoint, expected: fn(&Comm) -> bool) -> Option<(Msg, SocketAddr)> {
+    endpoint
+        .wait_for(expected, Duration::from_secs(5))
+        .expect("Error receiving reply")
+}
+
+/// Waits for the first node to answer a `QueryVin` and prints its answer.
+fn print_vin_record(endpoint: &Endpoint) {
+    let (msg, addr) = match wait_for_reply(endpoint, |s| matches!(s, Comm::VinRecord)) {
+        Some(s) => s,
+        None => {
+            println!("No node answered.");
+            return;
+        }
+    };
+    match deserialize::<Option<VehicleHistory>>(&msg.data) {
+        Ok(Some(s)) => {
+            println!("{} (answered by {addr})", s.current);
+            println!("Recorded in blocks: {:?}", s.block_ids);
+        }
+        Ok(None) => println!("Vehicle not found (answered by {addr})"),
+        Err(e) => println!("Malformed answer: {e}"),
+    }
+}
+
+/// Waits for the first node to answer a `GetPeers` and prints its peer table.
+fn print_peers(endpoint: &Endpoint) {
+    let (msg, addr) = match wait_for_reply(endpoint, |s| matches!(s, Comm::Peers)) {
+        Some(s) => s,
+        None => {
+            println!("No node answered.");
+            return;
+        }
+    };
+    let peers = match deserialize::<Vec<PeerInfo>>(&msg.data) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Malformed answer: {e}");
+            return;
+        }
+    };
+    println!("{} peers known to {addr}:", peers.len());
+    for peer in peers {
+        let seed = if peer.seed { " (seed)" } else { "" };
+        println!(
+            "{} {} last seen: {}{seed}",
+            peer.addr, peer.name, peer.last_seen
+        );
+    }
+}
+
+/// Waits for the first node to answer a `GetTraffic` and prints how many
+/// messages it received and dropped per host.
+fn print_traffic(endpoint: &Endpoint) {
+    let (msg, addr) = match wait_for_reply(endpoint, |s| matches!(s, Comm::Traffic)) {
+        Some(s) => s,
+        None => {
+            println!("No node answered.");
+            return;
+        }
+    };
+    let sources = match deserialize::<Vec<SourceStats>>(&msg.data) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Malformed answer: {e}");
+            return;
+        }
+    };
+    println!("Messages received by {addr} from {} hosts:", sources.len());
+    for source in sources {
+        println!(
+            "{} received: {} dropped: {}",
+            source.addr, source.received, source.dropped
+        );
+    }
+}
+
+/// Waits for the first node to answer a `GetBans` or `ClearBans` and prints
+/// its ban list.
+fn print_bans(endpoint: &Endpoint) {
+    let (msg, addr) = match wait_for_reply(endpoint, |s| matches!(s, Comm::Bans)) {
+        Some(s) => s,
+        None => {
+            println!("No node answered.");
+            return;
+        }
+    };
+    let entries = match deserialize::<Vec<BanEntry>>(&msg.data) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Malformed answer: {e}");
+            return;
+        }
+    };
+    println!("{} hosts scored by {addr}:", entries.len());
+    for entry in entries {
+        let banned = match entry.banned_until {
+            Some(s) => format!(" banned until: {s}"),
+            None => String::new(),
+        };
+        println!("{} score: {}{banned}", entry.addr, entry.score);
+    }
+}
+
+/// Writes blocks of the chain stored in the node log `argv[2]` to `argv[3]`,
+/// optionally limited to the heights `argv[4]` to `argv[5]`.
+fn export(argv: &[String], params: ChainParams) {
+    if argv.len() < 4 {
+        println!("Usage: EXPORT NODE_LOG OUT_FILE [FROM [TO]]");
+        return;
+    }
+    let from = match argv.get(4) {
+        Some(s) => s.parse().expect("Unexpected height!"),
+        None => 0,
+    };
+    let to = argv.get(5).map(|s| s.parse().expect("Unexpected height!"));
+
+    let chain = match ChainState::open(params, &argv[2]) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error opening node log: {e}");
+            return;
+        }
+    };
+    match export_chain(chain.store(), from, to, &argv[3]) {
+        Ok(s) => println!("Exported {s} blocks to {}", argv[3]),
+        Err(e) => println!("Export failed: {e}"),
+    }
+}
+
+/// Verifies the export `argv[2]` and adds it to the node log `argv[3]`, so a
+/// node started on that log begins with the imported chain.
+fn import(argv: &[String], params: ChainParams) {
+    if argv.len() < 4 {
+        println!("Usage: IMPORT IN_FILE NODE_LOG");
+        return;
+    }
+    let mut chain = match ChainState::open(params, &argv[3]) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error opening node log: {e}");
+            return;
+        }
+    };
+    match import_chain(&argv[2], &mut chain) {
+        Ok(update) => {
+            if let Some(e) = update.error {
+                println!("Import stopped early: {e}");
+            }
+            println!("Imported chain, tip is now {}", chain.tip());
+        }
+        Err(e) => println!("Import failed: {e}"),
+    }
 }
 
 fn main() {
     let mut rng = rand::thread_rng();
 
-    let argv: Vec<String> = env::args().collect();
-    let socket: UdpSocket = UdpSocket::bind("192.168.128.253:8000").expect("Error while binding");
+    let args: Vec<String> = env::args().collect();
+    let (net, argv) = NetConfig::load(&args).expect("Invalid network configuration");
+    let params = ChainParams::from_env().expect("Invalid chain parameters");
 
     if argv.len() < 2 {
-        println!("Please provide at least one argument:\nDUMP\nCAR\nCONT");
+        println!(
+            "Please provide at least one argument:\nDUMP\nCAR\nCONT\nCALC\nVIN\nPEERS\nTRAFFIC\nBANS\nUNBAN\nEXPORT\nIMPORT"
+        );
         return;
     }
 
+    // Export and import work on files, they don't need the network.
+    match argv[1].to_uppercase().as_str() {
+        "EXPORT" => return export(&argv, params),
+        "IMPORT" => return import(&argv, params),
+        _ => {}
+    }
+
+    let transport = UdpTransport::client(&net).expect("Error while binding");
+    let endpoint = Endpoint::new(Arc::new(transport)).with_key(ClusterKey::from_config(&net));
+    let chain_id = params.chain_id;
+
     match argv[1].to_uppercase().as_str() {
         "DUMP" => {
-            send_data(
-                socket,
-                Msg {
-                    command: Comm::PrintChain,
-                    data: Vec::new(),
-                },
-            );
-            return;
+            send_data(&endpoint, Msg::new(&chain_id, Comm::PrintChain, Vec::new()));
         }
         "CAR" => {
+            let vin = match argv.get(2) {
+                Some(s) => Vin::parse(s).expect("VIN must have 17 letters or digits!"),
+                None => random_vin(&mut rng),
+            };
+            let distance = match argv.get(3) {
+                Some(s) => s.parse().expect("Unexpected distance!"),
+                None => rng.gen_range(0..1000000),
+            };
+            println!("Car VIN: {vin}");
             let data = BlockData::Car(Car::new(
                 Some(NAMES[rng.gen_range(0..9)].to_string()),
                 Some(NAMES[rng.gen_range(0..9)].to_string()),
-                Some(rng.gen_range(0..1000000)),
-                None,
+                Some(distance),
+                Some(vin),
             ));
             send_data(
-                socket,
-                Msg {
-                    command: Comm::DataToBlock,
-                    data: serialize(&data).unwrap(),
-                },
+                &endpoint,
+                Msg::new(&chain_id, Comm::DataToBlock, serialize(&data).unwrap()),
             );
         }
         "CONT" => {
@@ -93,30 +260,78 @@ fn main() {
             let data = BlockData::Contract(contract);
 
             send_data(
-                socket,
-                Msg {
-                    command: Comm::DataToBlock,
-                    data: serialize(&data).unwrap(),
-                },
+                &endpoint,
+                Msg::new(&chain_id, Comm::DataToBlock, serialize(&data).unwrap()),
             );
         }
         "CALC" => {
+            if argv.len() < 3 {
+                println!("Usage: CALC [ARGS...] BLOCK_ID[:ENTRY]");
+                return;
+            }
             let mut args: Vec<f64> = Vec::new();
-            for i in &argv[2..] {
+            for i in &argv[2..argv.len() - 1] {
                 args.push(i.parse().expect("Unexpected string!"));
             }
+            let (block_id, entry) = match argv[argv.len() - 1].split_once(':') {
+                Some((block_id, entry)) => (block_id, entry),
+                None => (argv[argv.len() - 1].as_str(), "0"),
+            };
 
             send_data(
-                socket,
-                Msg {
-                    command: Comm::CalcContract,
-                    data: serialize(&args).unwrap(),
-                },
+                &endpoint,
+                Msg::new(
+                    &chain_id,
+                    Comm::CalcContract,
+                    serialize(&ContractCall {
+                        block_id: block_id.parse().expect("Unexpected block id!"),
+                        entry: entry.parse().expect("Unexpected entry index!"),
+                        args,
+                    })
+                    .unwrap(),
+                ),
+            );
+        }
+        "VIN" => {
+            let vin = match argv.get(2).and_then(|s| Vin::parse(s)) {
+                Some(s) => s,
+                None => {
+                    println!("Usage: VIN VIN_NUMBER");
+                    return;
+                }
+            };
+            send_data(
+                &endpoint,
+                Msg::new(&chain_id, Comm::QueryVin, serialize(&vin).unwrap()),
             );
+            print_vin_record(&endpoint);
+        }
+        "PEERS" => {
+            send_data(&endpoint, Msg::new(&chain_id, Comm::GetPeers, Vec::new()));
+            print_peers(&endpoint);
+        }
+        "TRAFFIC" => {
+            send_data(&endpoint, Msg::new(&chain_id, Comm::GetTraffic, Vec::new()));
+            print_traffic(&endpoint);
+        }
+        "BANS" => {
+            send_data(&endpoint, Msg::new(&chain_id, Comm::GetBans, Vec::new()));
+            print_bans(&endpoint);
+        }
+        "UNBAN" => {
+            // Without an address every ban is lifted. Nodes without a
+            // cluster key only take this from their own host.
+            let addr: Option<IpAddr> = argv
+                .get(2)
+                .map(|s| s.parse().expect("Usage: UNBAN [IP_ADDRESS]"));
+            let node = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), net.listen.port());
+            let msg = Msg::new(&chain_id, Comm::ClearBans, serialize(&addr).unwrap());
+            endpoint.send_to(&msg, node).expect("Error sending message");
+            println!("Sent {:?} message to {node}", msg.command);
+            print_bans(&endpoint);
         }
         _ => {
             println!("Invalid argument.");
-            return;
         }
     }
 }
@@ -0,0 +1,195 @@
+use crate::config::NetConfig;
+use crate::datatypes::{BlockchainError, HASH_LEN};
+use crate::framing::next_message_id;
+use crate::ret_err;
+use bincode::{deserialize, serialize};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Messages stamped further than this many seconds from our clock are
+/// rejected, so captured messages can't be replayed later on.
+pub const MAX_MESSAGE_AGE: u64 = 60;
+
+/// Maximum number of tags kept to recognise replays within the window. Once
+/// it is reached, new messages are rejected until tags expire.
+const MAX_TAGS: usize = 65536;
+
+/// A serialized `Msg` with the proof that it was sent by a holder of the
+/// cluster key.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    timestamp: u64,
+    /// Makes the tag of every message unique, even of identical messages
+    /// sent within the same second.
+    nonce: u64,
+    payload: Vec<u8>,
+    tag: [u8; HASH_LEN],
+}
+
+/// Tags of the messages accepted within the last `MAX_MESSAGE_AGE` seconds.
+#[derive(Default)]
+struct SeenTags {
+    tags: HashSet<[u8; HASH_LEN]>,
+    /// Tags with their message timestamps, in the order they were accepted.
+    order: VecDeque<([u8; HASH_LEN], u64)>,
+}
+
+/// Shared secret of the nodes and clients of a cluster, authenticating every
+/// message with an HMAC-SHA256 over its timestamp, nonce and contents.
+pub struct ClusterKey {
+    key: Vec<u8>,
+    seen: Mutex<SeenTags>,
+}
+
+impl ClusterKey {
+    pub fn new(key: &[u8]) -> ClusterKey {
+        ClusterKey {
+            key: key.to_vec(),
+            seen: Mutex::new(SeenTags::default()),
+        }
+    }
+
+    /// Key set in `config`, `None` if messages aren't authenticated.
+    pub fn from_config(config: &NetConfig) -> Option<Arc<ClusterKey>> {
+        let key = config.cluster_key.as_ref()?;
+        Some(Arc::new(ClusterKey::new(key.as_bytes())))
+    }
+
+    fn tag(&self, timestamp: u64, nonce: u64, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(&timestamp.to_be_bytes());
+        mac.update(&nonce.to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+
+    /// Wraps the serialized message `payload`, stamped with `now`.
+    pub fn seal(&self, payload: &[u8], now: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let nonce = next_message_id();
+        let tag = self.tag(now, nonce, payload).finalize().into_bytes().into();
+        Ok(serialize(&Sealed {
+            timestamp: now,
+            nonce,
+            payload: payload.to_vec(),
+            tag,
+        })?)
+    }
+
+    /// Unwraps a message sealed with the same key within `MAX_MESSAGE_AGE`
+    /// seconds of `now`, and never seen before.
+    ///
+    /// Failures aren't offences: the sender address of a message that isn't
+    /// authenticated may be spoofed, so it mustn't get anyone banned.
+    pub fn open(&self, sealed: &[u8], now: u64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let sealed = match deserialize::<Sealed>(sealed) {
+            Ok(s) => s,
+            Err(_) => {
+                ret_err!("Message isn't sealed.");
+            }
+        };
+        if self
+            .tag(sealed.timestamp, sealed.nonce, &sealed.payload)
+            .verify_slice(&sealed.tag)
+            .is_err()
+        {
+            ret_err!("Wrong message tag.");
+        }
+        if sealed.timestamp.abs_diff(now) > MAX_MESSAGE_AGE {
+            ret_err!(format!(
+                "Message stamped {} is too far from our time {now}.",
+                sealed.timestamp
+            ));
+        }
+
+        let mut seen = match self.seen.lock() {
+            Ok(s) => s,
+            Err(_) => {
+                ret_err!("Seen tags lock is poisoned.");
+            }
+        };
+        while let Some((oldest, timestamp)) = seen.order.front().copied() {
+            if now.saturating_sub(timestamp) <= MAX_MESSAGE_AGE {
+                break;
+            }
+            seen.tags.remove(&oldest);
+            seen.order.pop_front();
+        }
+        if seen.tags.contains(&sealed.tag) {
+            ret_err!("Replayed message.");
+        }
+        // Forgetting tags that haven't expired would let their messages be
+        // replayed.
+        if seen.order.len() >= MAX_TAGS {
+            ret_err!("Too many messages within the replay window.");
+        }
+        seen.tags.insert(sealed.tag);
+        seen.order.push_back((sealed.tag, sealed.timestamp));
+        Ok(sealed.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClusterKey, MAX_MESSAGE_AGE, MAX_TAGS};
+    use crate::bans::offence_of;
+
+    #[test]
+    fn test_sealed_message_opens_once() {
+        let key = ClusterKey::new(b"cluster secret");
+        let sealed = key.seal(b"payload", 1000).unwrap();
+        assert_eq!(key.open(&sealed, 1010).unwrap(), b"payload");
+        let replay = key.open(&sealed, 1011).unwrap_err();
+        assert_eq!(offence_of(replay.as_ref()), None);
+
+        // Identical messages still get their own tags.
+        let again = key.seal(b"payload", 1000).unwrap();
+        assert!(key.open(&again, 1011).is_ok());
+    }
+
+    #[test]
+    fn test_full_tag_cache_rejects_until_tags_expire() {
+        let key = ClusterKey::new(b"cluster secret");
+        {
+            let mut seen = key.seen.lock().unwrap();
+            for i in 0..MAX_TAGS {
+                let mut tag = [0; 32];
+                tag[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                seen.tags.insert(tag);
+                seen.order.push_back((tag, 1000));
+            }
+        }
+        let sealed = key.seal(b"payload", 1010).unwrap();
+        assert!(key.open(&sealed, 1010).is_err());
+        assert!(key.open(&sealed, 1001 + MAX_MESSAGE_AGE).is_ok());
+    }
+
+    #[test]
+    fn test_stale_message_rejected() {
+        let key = ClusterKey::new(b"cluster secret");
+        let sealed = key.seal(b"payload", 1000).unwrap();
+        assert!(key.open(&sealed, 1001 + MAX_MESSAGE_AGE).is_err());
+        let early = key.seal(b"payload", 1001 + MAX_MESSAGE_AGE).unwrap();
+        assert!(key.open(&early, 1000).is_err());
+    }
+
+    #[test]
+    fn test_foreign_and_tampered_messages_rejected() {
+        let key = ClusterKey::new(b"cluster secret");
+        let other = ClusterKey::new(b"other secret");
+        let foreign = other.seal(b"payload", 1000).unwrap();
+        let error = key.open(&foreign, 1000).unwrap_err();
+        assert_eq!(offence_of(error.as_ref()), None);
+
+        let mut tampered = key.seal(b"payload", 1000).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(key.open(&tampered, 1000).is_err());
+
+        let error = key.open(b"plain message", 1000).unwrap_err();
+        assert_eq!(offence_of(error.as_ref()), None);
+    }
+}
@@ -0,0 +1,259 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Score at which a peer gets banned.
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Maximum number of hosts a `BanList` keeps scores for.
+const MAX_SCORED: usize = 1024;
+
+/// Ways a peer can break the protocol.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// A message or its payload couldn't be deserialized.
+    Malformed,
+    /// A block failed verification.
+    InvalidBlock,
+    /// A peer's download delivered less work than its tip announced.
+    FalseTip,
+}
+
+impl Offence {
+    /// Score added to the peer for each offence.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Offence::Malformed => 10,
+            Offence::InvalidBlock => 50,
+            Offence::FalseTip => 25,
+        }
+    }
+}
+
+/// Error of a handler rejecting a message because the peer misbehaved.
+#[derive(Debug)]
+pub struct Misbehaviour {
+    pub offence: Offence,
+    pub reason: String,
+}
+
+impl Misbehaviour {
+    pub fn new(offence: Offence, reason: impl fmt::Display) -> Misbehaviour {
+        Misbehaviour {
+            offence,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.offence, self.reason)
+    }
+}
+
+impl std::error::Error for Misbehaviour {}
+
+/// Offence behind `error`, if it was caused by the peer: a `Misbehaviour`
+/// or a payload that didn't deserialize.
+pub fn offence_of(error: &(dyn std::error::Error + 'static)) -> Option<Offence> {
+    if let Some(s) = error.downcast_ref::<Misbehaviour>() {
+        return Some(s.offence);
+    }
+    if error.downcast_ref::<bincode::Error>().is_some() {
+        return Some(Offence::Malformed);
+    }
+    None
+}
+
+/// Score and ban of a host.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BanEntry {
+    pub addr: IpAddr,
+    pub score: u32,
+    /// Time of the latest offence.
+    pub last_offence: u64,
+    /// End of the ban, `None` while the score is below the threshold.
+    pub banned_until: Option<u64>,
+}
+
+/// Misbehaviour scores of peers, keyed by host.
+///
+/// Every offence adds its penalty to the host's score, reaching
+/// `BAN_THRESHOLD` bans the host for `ban_time` seconds. Scores are
+/// forgotten once the host has behaved for `ban_time` seconds.
+pub struct BanList {
+    entries: HashMap<IpAddr, BanEntry>,
+    ban_time: u64,
+}
+
+impl BanList {
+    pub fn new(ban_time: u64) -> BanList {
+        BanList {
+            entries: HashMap::new(),
+            ban_time,
+        }
+    }
+
+    pub fn is_banned(&self, addr: IpAddr, now: u64) -> bool {
+        match self.entries.get(&addr).and_then(|entry| entry.banned_until) {
+            Some(s) => now < s,
+            None => false,
+        }
+    }
+
+    /// Adds the penalty of `offence` to the score of `addr`. Returns `true`
+    /// if this got the host banned.
+    ///
+    /// A full table makes room by forgetting the lowest score that isn't
+    /// banned, new hosts are only ignored once every host is banned.
+    pub fn penalize(&mut self, addr: IpAddr, offence: Offence, now: u64) -> bool {
+        self.forget_expired(now);
+        if !self.entries.contains_key(&addr)
+            && self.entries.len() >= MAX_SCORED
+            && !self.forget_lowest()
+        {
+            return false;
+        }
+        let ban_time = self.ban_time;
+        let entry = self.entries.entry(addr).or_insert(BanEntry {
+            addr,
+            score: 0,
+            last_offence: now,
+            banned_until: None,
+        });
+        if entry.banned_until.is_some() {
+            return false;
+        }
+        entry.score = entry.score.saturating_add(offence.penalty());
+        entry.last_offence = now;
+        if entry.score < BAN_THRESHOLD {
+            return false;
+        }
+        entry.banned_until = Some(now + ban_time);
+        warn!("Banning {addr} for {ban_time}s, score {}", entry.score);
+        true
+    }
+
+    /// Hosts with a score, sorted by address.
+    pub fn entries(&self) -> Vec<BanEntry> {
+        let mut entries: Vec<BanEntry> = self.entries.values().cloned().collect();
+        entries.sort_by_key(|entry| entry.addr);
+        entries
+    }
+
+    /// Forgets the score and ban of `addr`, or of every host if `None`.
+    /// Returns the number of hosts cleared.
+    pub fn clear(&mut self, addr: Option<IpAddr>) -> usize {
+        match addr {
+            Some(s) => self.entries.remove(&s).map_or(0, |_| 1),
+            None => {
+                let cleared = self.entries.len();
+                self.entries.clear();
+                cleared
+            }
+        }
+    }
+
+    /// Forgets the lowest score of a host that isn't banned, the one with the
+    /// oldest offence among equal scores. Returns `false` if all are banned.
+    fn forget_lowest(&mut self) -> bool {
+        let lowest = self
+            .entries
+            .values()
+            .filter(|entry| entry.banned_until.is_none())
+            .min_by_key(|entry| (entry.score, entry.last_offence))
+            .map(|entry| entry.addr);
+        match lowest {
+            Some(s) => self.entries.remove(&s).is_some(),
+            None => false,
+        }
+    }
+
+    /// Drops bans that ran out and scores older than `ban_time`.
+    fn forget_expired(&mut self, now: u64) {
+        let ban_time = self.ban_time;
+        self.entries.retain(|_, entry| match entry.banned_until {
+            Some(s) => now < s,
+            None => now.saturating_sub(entry.last_offence) < ban_time,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{offence_of, BanList, Misbehaviour, Offence, MAX_SCORED};
+    use bincode::deserialize;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_repeated_offences_ban_until_expiry() {
+        let mut bans = BanList::new(600);
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        let other: IpAddr = "10.0.0.3".parse().unwrap();
+        assert!(!bans.penalize(peer, Offence::InvalidBlock, 1000));
+        assert!(!bans.penalize(other, Offence::Malformed, 1000));
+        assert!(!bans.is_banned(peer, 1000));
+        assert!(bans.penalize(peer, Offence::InvalidBlock, 1010));
+        assert!(bans.is_banned(peer, 1010));
+        assert!(!bans.is_banned(other, 1010));
+
+        // The ban runs out, and so does the score of the other host.
+        assert!(!bans.is_banned(peer, 1610));
+        assert!(!bans.penalize(peer, Offence::Malformed, 1610));
+        assert_eq!(bans.entries().len(), 1);
+        assert_eq!(bans.entries()[0].score, 10);
+    }
+
+    #[test]
+    fn test_clearing_bans() {
+        let mut bans = BanList::new(600);
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        let other: IpAddr = "10.0.0.3".parse().unwrap();
+        bans.penalize(peer, Offence::InvalidBlock, 1000);
+        bans.penalize(peer, Offence::InvalidBlock, 1000);
+        bans.penalize(other, Offence::FalseTip, 1000);
+        assert_eq!(bans.clear(Some(peer)), 1);
+        assert!(!bans.is_banned(peer, 1000));
+        assert_eq!(bans.clear(None), 1);
+        assert!(bans.entries().is_empty());
+    }
+
+    #[test]
+    fn test_full_table_forgets_lowest_score() {
+        let mut bans = BanList::new(3600);
+        let host = |i: usize| IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i as u32));
+        for i in 0..MAX_SCORED {
+            bans.penalize(host(i), Offence::FalseTip, 1000 + i as u64);
+        }
+        bans.penalize(host(1), Offence::Malformed, 1100);
+        bans.penalize(host(2), Offence::InvalidBlock, 1100);
+        bans.penalize(host(2), Offence::InvalidBlock, 1100);
+
+        // Host 0 has the lowest score and the oldest offence.
+        let newcomer = host(MAX_SCORED);
+        assert!(!bans.penalize(newcomer, Offence::InvalidBlock, 1200));
+        assert!(bans.penalize(newcomer, Offence::InvalidBlock, 1200));
+        let entries = bans.entries();
+        assert_eq!(entries.len(), MAX_SCORED);
+        assert!(!entries.iter().any(|entry| entry.addr == host(0)));
+        assert!(bans.is_banned(host(2), 1200));
+    }
+
+    #[test]
+    fn test_peer_errors_classified() {
+        let boxed: Box<dyn std::error::Error> =
+            Box::new(Misbehaviour::new(Offence::FalseTip, "less work"));
+        assert_eq!(offence_of(boxed.as_ref()), Some(Offence::FalseTip));
+
+        let malformed =
+            || -> Result<u64, Box<dyn std::error::Error>> { Ok(deserialize::<u64>(&[1, 2])?) };
+        let error = malformed().unwrap_err();
+        assert_eq!(offence_of(error.as_ref()), Some(Offence::Malformed));
+
+        let io: Box<dyn std::error::Error> = Box::new(std::io::Error::other("timeout"));
+        assert_eq!(offence_of(io.as_ref()), None);
+    }
+}
@@ -0,0 +1,761 @@
+use crate::datatypes::BlockchainError;
+use crate::datatypes::HASH_LEN;
+use crate::difficulty::{bits_to_target, block_work, hash_meets_target};
+use crate::storage::{ChainStore, FileStore, MemoryStore};
+use crate::vin_index::VinIndex;
+use crate::{
+    ancestor_window, block_hash, ret_err, verify_entries, verify_header, verify_new_block, Block,
+    ChainParams, Clock,
+};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Maximum number of blocks waiting for their parent.
+const MAX_ORPHANS: usize = 100;
+
+/// Maximum number of orphans kept from a single host.
+const MAX_ORPHANS_PER_SOURCE: usize = 10;
+
+/// Total proof of work contained in `chain`.
+pub fn chain_work(chain: &[Block]) -> u128 {
+    chain.iter().fold(0, |acc: u128, block| {
+        acc.saturating_add(block_work(block.bits))
+    })
+}
+
+/// Number of most recent blocks listed one by one in a locator.
+const LOCATOR_DENSE: usize = 10;
+
+/// Hashes describing `chain` to a peer, tip first: the last ten blocks, then
+/// blocks spaced exponentially further apart, always ending with genesis.
+/// The peer answers from the first hash it knows, so the fork point is found
+/// without sending the whole chain.
+pub fn locator(chain: &[Block]) -> Vec<[u8; HASH_LEN]> {
+    let mut hashes = Vec::new();
+    let mut step = 1;
+    let mut height = chain.len().saturating_sub(1);
+    while height > 0 {
+        hashes.push(chain[height].hash);
+        if hashes.len() >= LOCATOR_DENSE {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+    if let Some(genesis) = chain.first() {
+        hashes.push(genesis.hash);
+    }
+    hashes
+}
+
+/// Result of switching the active chain to a new tip.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reorg {
+    /// Blocks removed from the active chain, lowest height first.
+    pub disconnected: Vec<Block>,
+    /// Blocks added to the active chain, lowest height first.
+    pub connected: Vec<Block>,
+}
+
+impl Reorg {
+    /// Extends this reorg with `next`, which was applied right after it.
+    fn append(&mut self, next: Reorg) {
+        for block in next.disconnected.into_iter().rev() {
+            if self
+                .connected
+                .last()
+                .is_some_and(|last| last.hash == block.hash)
+            {
+                self.connected.pop();
+            } else {
+                self.disconnected.insert(0, block);
+            }
+        }
+        self.connected.extend(next.connected);
+    }
+}
+
+/// Outcome of adding a batch of blocks with `ChainState::add_chain`.
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    /// Change of the active chain made by the blocks that were added.
+    pub reorg: Option<Reorg>,
+    /// Why adding stopped early. The blocks before the failing one stay
+    /// added, the ones after it are skipped.
+    pub error: Option<Box<dyn std::error::Error>>,
+}
+
+/// Block waiting for its parent and the host it came from, `None` for
+/// blocks that didn't come from a peer.
+struct Orphan {
+    block: Block,
+    source: Option<IpAddr>,
+}
+
+/// Checks what can be checked of a block whose ancestors are unknown: its
+/// hash must match the header and meet the target it claims, which may not
+/// be easier than the limit of the chain.
+fn verify_orphan(block: &Block, params: &ChainParams) -> Result<(), Box<dyn std::error::Error>> {
+    if bits_to_target(block.bits) > bits_to_target(params.pow_limit_bits) {
+        ret_err!("Orphan block claims a target easier than the limit.");
+    }
+    if block_hash(block)? != block.hash {
+        ret_err!("Stored hash doesn't match block header.");
+    }
+    if !hash_meets_target(&block.hash, block.bits) {
+        ret_err!("Orphan block hash doesn't meet its target.");
+    }
+    Ok(())
+}
+
+struct TreeEntry {
+    block: Block,
+    /// Work accumulated from the genesis block up to and including this one.
+    work: u128,
+}
+
+/// Tree of every valid block known to the node, one branch of which is
+/// the active chain.
+///
+/// Fork choice picks the tip with the most accumulated work. When two tips
+/// carry the same work the active one is kept, between two inactive tips
+/// the one with the lower hash wins. Blocks whose parent is not known yet
+/// wait in an orphan pool and are connected once the parent arrives.
+///
+/// The active chain lives in a `ChainStore`, the rest of the tree and the
+/// orphans only in memory.
+pub struct ChainState {
+    pub params: ChainParams,
+    tree: HashMap<[u8; HASH_LEN], TreeEntry>,
+    /// Hash of the tip with the most work, kept up to date on every insert.
+    best: [u8; HASH_LEN],
+    /// Oldest first.
+    orphans: Vec<Orphan>,
+    active: Box<dyn ChainStore>,
+    /// Vehicles recorded on the active chain.
+    vins: VinIndex,
+    /// Time new blocks are checked against.
+    clock: Clock,
+}
+
+impl ChainState {
+    /// Chain holding only the genesis block described by `params`.
+    pub fn new(params: ChainParams) -> ChainState {
+        let genesis = params.genesis_block();
+        let mut active = MemoryStore::new();
+        active
+            .append(genesis.clone())
+            .expect("Empty store accepts the genesis block");
+        let mut tree = HashMap::new();
+        tree.insert(
+            genesis.hash,
+            TreeEntry {
+                block: genesis.clone(),
+                work: block_work(genesis.bits),
+            },
+        );
+        ChainState {
+            params,
+            best: genesis.hash,
+            tree,
+            orphans: Vec::new(),
+            active: Box::new(active),
+            vins: VinIndex::new(),
+            clock: Clock::system(),
+        }
+    }
+
+    /// Checks block timestamps against `clock` instead of the wall clock.
+    pub fn with_clock(mut self, clock: Clock) -> ChainState {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Chain whose active part is kept in `store`.
+    ///
+    /// Stored blocks are verified again as if they were just received. The
+    /// first one that fails is rolled back from the store together with
+    /// every block above it.
+    pub fn with_store(
+        params: ChainParams,
+        mut store: Box<dyn ChainStore>,
+    ) -> Result<ChainState, Box<dyn std::error::Error>> {
+        let genesis = params.genesis_block();
+        match store.get_by_height(0) {
+            Some(s) if *s != genesis => {
+                ret_err!("Stored chain starts with a different genesis block.");
+            }
+            Some(_) => {}
+            None => store.append(genesis)?,
+        }
+
+        let mut chain = ChainState::new(params);
+        for block in &store.blocks()[1..] {
+            if let Err(e) = chain.add_block(block.clone()) {
+                warn!("Stored block fails verification: {e}");
+                break;
+            }
+        }
+        let dropped = store.rollback(chain.tip().id)?;
+        if !dropped.is_empty() {
+            warn!("Rolled back {} stored blocks", dropped.len());
+        }
+        info!("Loaded stored chain up to height {}", chain.tip().id);
+        chain.active = store;
+        Ok(chain)
+    }
+
+    /// Chain backed by a `FileStore` at `path`.
+    pub fn open<P: AsRef<Path>>(
+        params: ChainParams,
+        path: P,
+    ) -> Result<ChainState, Box<dyn std::error::Error>> {
+        ChainState::with_store(params, Box::new(FileStore::open(path)?))
+    }
+
+    /// Blocks of the active chain, starting from the genesis block.
+    pub fn blocks(&self) -> &[Block] {
+        self.active.blocks()
+    }
+
+    /// Store holding the active chain.
+    pub fn store(&self) -> &dyn ChainStore {
+        self.active.as_ref()
+    }
+
+    pub fn vins(&self) -> &VinIndex {
+        &self.vins
+    }
+
+    /// Last block of the active chain.
+    pub fn tip(&self) -> &Block {
+        self.active
+            .tip()
+            .expect("Active chain always holds the genesis block")
+    }
+
+    /// Accumulated work of the active chain.
+    pub fn work(&self) -> u128 {
+        self.tree[&self.tip().hash].work
+    }
+
+    /// Looks up any known block, active or not, by its hash.
+    pub fn get(&self, hash: &[u8; HASH_LEN]) -> Option<&Block> {
+        self.tree.get(hash).map(|entry| &entry.block)
+    }
+
+    /// Checks whether a block with `hash` is in the tree or the orphan pool.
+    pub fn contains(&self, hash: &[u8; HASH_LEN]) -> bool {
+        self.tree.contains_key(hash) || self.orphans.iter().any(|orphan| orphan.block.hash == *hash)
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// Tip with the most accumulated work.
+    pub fn best_tip(&self) -> &Block {
+        &self.tree[&self.best].block
+    }
+
+    fn is_active(&self, block: &Block) -> bool {
+        match self.active.get_by_height(block.id) {
+            Some(s) => s.hash == block.hash,
+            None => false,
+        }
+    }
+
+    /// Blocks from the tip `hash` back to, but excluding, the active chain,
+    /// lowest height first.
+    fn branch_to(&self, hash: &[u8; HASH_LEN]) -> Vec<Block> {
+        let mut branch = Vec::new();
+        let mut current = self.tree.get(hash);
+        while let Some(entry) = current {
+            if self.is_active(&entry.block) {
+                break;
+            }
+            branch.push(entry.block.clone());
+            current = self.tree.get(&entry.block.prev_hash);
+        }
+        branch.reverse();
+        branch
+    }
+
+    /// The blocks up to and including `hash` that a child of it is checked
+    /// against: the last `ancestor_window` blocks of its branch, down to the
+    /// genesis block at most. Only those blocks are cloned, however long the
+    /// branch.
+    fn ancestors_of(&self, hash: &[u8; HASH_LEN]) -> Vec<Block> {
+        let window = ancestor_window(&self.params);
+        let mut branch = Vec::new();
+        let mut current = self.tree.get(hash);
+        while let Some(entry) = current {
+            if branch.len() == window || self.is_active(&entry.block) {
+                break;
+            }
+            branch.push(&entry.block);
+            current = self.tree.get(&entry.block.prev_hash);
+        }
+        let mut ancestors = Vec::new();
+        if branch.len() < window {
+            // The branch reached the active chain, which supplies the rest.
+            let fork_height = match branch.last() {
+                Some(s) => s.id as usize,
+                None => self.tree[hash].block.id as usize + 1,
+            };
+            let active = &self.active.blocks()[..fork_height];
+            let wanted = window - branch.len();
+            ancestors = active[active.len().saturating_sub(wanted)..].to_vec();
+        }
+        ancestors.extend(branch.into_iter().rev().cloned());
+        ancestors
+    }
+
+    /// Validates `block` against its ancestors and inserts it into the tree.
+    fn insert(&mut self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
+        let parent_work = match self.tree.get(&block.prev_hash) {
+            Some(s) => s.work,
+            None => {
+                ret_err!("Block doesn't descend from the genesis block.");
+            }
+        };
+        let now = self.clock.now();
+        let block = if self.tip().hash == block.prev_hash {
+            verify_new_block(block, self.active.blocks(), &self.params, now)?
+        } else {
+            let ancestors = self.ancestors_of(&block.prev_hash);
+            verify_header(&block, &ancestors, &self.params, now)?;
+            verify_entries(block)?
+        };
+
+        let hash = block.hash;
+        let work = parent_work.saturating_add(block_work(block.bits));
+        self.tree.insert(hash, TreeEntry { block, work });
+
+        // Same order as the fork choice: more work wins, on a tie the active
+        // tip stays, between inactive tips the lower hash wins.
+        let best = &self.tree[&self.best];
+        if work > best.work
+            || (work == best.work && self.best != self.tip().hash && hash < self.best)
+        {
+            self.best = hash;
+        }
+        Ok(())
+    }
+
+    /// Connects every orphan that descends from the block with `hash`.
+    fn connect_orphans(&mut self, hash: [u8; HASH_LEN]) {
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            let (children, rest): (Vec<Orphan>, Vec<Orphan>) = self
+                .orphans
+                .drain(..)
+                .partition(|orphan| orphan.block.prev_hash == parent);
+            self.orphans = rest;
+            for child in children {
+                let child_hash = child.block.hash;
+                match self.insert(child.block) {
+                    Ok(()) => parents.push(child_hash),
+                    Err(e) => debug!("Dropping invalid orphan: {e}"),
+                }
+            }
+        }
+    }
+
+    /// Switches the active chain to the best tip if it isn't already.
+    fn activate_best(&mut self) -> Result<Option<Reorg>, Box<dyn std::error::Error>> {
+        let best = self.best_tip().hash;
+        if self.tip().hash == best {
+            return Ok(None);
+        }
+
+        let connected = self.branch_to(&best);
+        let fork_height = match connected.first() {
+            Some(s) => s.id,
+            None => self.active.len() as u32,
+        };
+        let disconnected = self.active.rollback(fork_height - 1)?;
+        for block in disconnected.iter().rev() {
+            self.vins.disconnect(block);
+        }
+        for block in &connected {
+            self.active.append(block.clone())?;
+            self.vins.connect(block);
+        }
+        Ok(Some(Reorg {
+            disconnected,
+            connected,
+        }))
+    }
+
+    /// Keeps `block` from `source` until its parent arrives. A host that
+    /// already has `MAX_ORPHANS_PER_SOURCE` orphans waiting loses its oldest
+    /// one, and when the pool is full the host with the most orphans does.
+    fn keep_orphan(&mut self, block: Block, source: Option<IpAddr>) {
+        let mut counts: HashMap<Option<IpAddr>, usize> = HashMap::new();
+        for orphan in &self.orphans {
+            *counts.entry(orphan.source).or_default() += 1;
+        }
+        let evicted = if counts
+            .get(&source)
+            .is_some_and(|n| *n >= MAX_ORPHANS_PER_SOURCE)
+        {
+            Some(source)
+        } else if self.orphans.len() >= MAX_ORPHANS {
+            counts
+                .into_iter()
+                .max_by_key(|(_, n)| *n)
+                .map(|(source, _)| source)
+        } else {
+            None
+        };
+        if let Some(evicted) = evicted {
+            if let Some(oldest) = self.orphans.iter().position(|o| o.source == evicted) {
+                self.orphans.remove(oldest);
+            }
+        }
+        self.orphans.push(Orphan { block, source });
+    }
+
+    /// Validates and stores `block`, keeping it as an orphan if its parent
+    /// is unknown. Returns the change of the active chain, if any.
+    pub fn add_block(&mut self, block: Block) -> Result<Option<Reorg>, Box<dyn std::error::Error>> {
+        self.add_block_from(block, None)
+    }
+
+    /// Like `add_block`, for a block received from the host `source`.
+    pub fn add_block_from(
+        &mut self,
+        block: Block,
+        source: Option<IpAddr>,
+    ) -> Result<Option<Reorg>, Box<dyn std::error::Error>> {
+        if self.contains(&block.hash) {
+            return Ok(None);
+        }
+        if block.prev_hash == [0; HASH_LEN] {
+            ret_err!("Genesis block doesn't match chain parameters.");
+        }
+        if !self.tree.contains_key(&block.prev_hash) {
+            verify_orphan(&block, &self.params)?;
+            debug!("Keeping orphan block: {block}");
+            self.keep_orphan(block, source);
+            return Ok(None);
+        }
+
+        let hash = block.hash;
+        self.insert(block)?;
+        self.connect_orphans(hash);
+        self.activate_best()
+    }
+
+    /// Adds every block of `chain` in order, stopping at the first one that
+    /// fails. The change of the active chain is reported even then, as the
+    /// blocks before the failing one may already have changed it.
+    pub fn add_chain(&mut self, chain: Vec<Block>) -> ChainUpdate {
+        let mut reorg = Reorg::default();
+        let mut error = None;
+        for block in chain {
+            match self.add_block(block) {
+                Ok(Some(s)) => reorg.append(s),
+                Ok(None) => {}
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        ChainUpdate {
+            reorg: (reorg != Reorg::default()).then_some(reorg),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chain_work, locator, ChainState, MAX_ORPHANS_PER_SOURCE};
+    use crate::test_utils::{add_all, easy_params, mine_at, mine_chain, mine_on, remine};
+    use crate::Block;
+    use crate::ChainParams;
+    use crate::{ancestor_window, block_hash};
+
+    /// Recomputes the hash after a header change, without mining.
+    fn remine_hash(block: &mut Block) {
+        block.hash = block_hash(block).unwrap();
+    }
+
+    fn params() -> ChainParams {
+        ChainParams {
+            retarget_interval: 2,
+            ..easy_params()
+        }
+    }
+
+    #[test]
+    fn test_more_work_beats_more_blocks() {
+        let params = params();
+
+        // Slow blocks keep the target at the limit, fast ones make it harder.
+        let start = params.genesis_timestamp;
+        let mut long = vec![params.genesis_block()];
+        for i in 1..6 {
+            let block = mine_at(&long, &params, "Long", start + i * 1_000);
+            long.push(block);
+        }
+        let mut short = vec![params.genesis_block()];
+        for i in 1..5 {
+            let block = mine_at(&short, &params, "Short", start + i);
+            short.push(block);
+        }
+        assert!(chain_work(&short) > chain_work(&long));
+
+        let mut state = ChainState::new(params);
+        assert!(add_all(&mut state, long.clone()).is_some());
+        let reorg = add_all(&mut state, short.clone()).unwrap();
+        assert_eq!(reorg.disconnected, long[1..].to_vec());
+        assert_eq!(reorg.connected, short[1..].to_vec());
+        assert_eq!(Some(state.best_tip()), short.last());
+        assert_eq!(state.blocks(), &short[..]);
+    }
+
+    #[test]
+    fn test_tie_keeps_active_chain() {
+        let params = easy_params();
+        let first = mine_chain(2, &params);
+        let mut second = first[..1].to_vec();
+        second.push(mine_on(&second, &params, "Other"));
+
+        let mut state = ChainState::new(params.clone());
+        add_all(&mut state, first.clone());
+        assert!(add_all(&mut state, second).is_none());
+        assert_eq!(Some(state.best_tip()), first.last());
+    }
+
+    #[test]
+    fn test_block_extends_active_chain() {
+        let params = easy_params();
+        let chain = mine_chain(3, &params);
+        let mut state = ChainState::new(params.clone());
+        for block in &chain[1..] {
+            let reorg = state.add_block(block.clone()).unwrap().unwrap();
+            assert!(reorg.disconnected.is_empty());
+            assert_eq!(reorg.connected, vec![block.clone()]);
+        }
+        assert_eq!(state.blocks(), &chain[..]);
+        assert!(state.add_block(chain[2].clone()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_orphans_connected_when_parent_arrives() {
+        let params = easy_params();
+        let chain = mine_chain(5, &params);
+        let mut state = ChainState::new(params);
+
+        assert!(state.add_block(chain[1].clone()).unwrap().is_some());
+        assert!(state.add_block(chain[4].clone()).unwrap().is_none());
+        assert!(state.add_block(chain[3].clone()).unwrap().is_none());
+        assert_eq!(state.orphan_count(), 2);
+
+        let reorg = state.add_block(chain[2].clone()).unwrap().unwrap();
+        assert_eq!(reorg.connected, chain[2..].to_vec());
+        assert_eq!(state.orphan_count(), 0);
+        assert_eq!(state.blocks(), &chain[..]);
+    }
+
+    #[test]
+    fn test_orphan_needs_proof_of_work() {
+        let params = easy_params();
+        let chain = mine_chain(3, &params);
+        let mut state = ChainState::new(params.clone());
+
+        let mut unmined = chain[2].clone();
+        unmined.nonce ^= 1;
+        assert!(state.add_block(unmined).is_err());
+        let mut hard = chain[2].clone();
+        hard.bits = 0x0100_0001;
+        remine_hash(&mut hard);
+        assert!(state.add_block(hard).is_err());
+        let mut easy = chain[2].clone();
+        easy.bits = 0x20ff_ffff;
+        remine_hash(&mut easy);
+        assert!(state.add_block(easy).is_err());
+        assert_eq!(state.orphan_count(), 0);
+    }
+
+    #[test]
+    fn test_orphans_limited_per_source() {
+        let params = easy_params();
+        let chain = mine_chain(20, &params);
+        let mut state = ChainState::new(params);
+        let flooder = Some("10.0.0.1".parse().unwrap());
+        let honest = Some("10.0.0.2".parse().unwrap());
+
+        for block in &chain[3..18] {
+            state.add_block_from(block.clone(), flooder).unwrap();
+        }
+        assert_eq!(state.orphan_count(), MAX_ORPHANS_PER_SOURCE);
+        // The oldest orphans of the flooder made room for its newest.
+        assert!(!state.contains(&chain[3].hash));
+        assert!(state.contains(&chain[17].hash));
+
+        state.add_block_from(chain[2].clone(), honest).unwrap();
+        assert_eq!(state.orphan_count(), MAX_ORPHANS_PER_SOURCE + 1);
+        assert!(state.add_block(chain[1].clone()).unwrap().is_some());
+        assert_eq!(state.tip(), &chain[2]);
+    }
+
+    #[test]
+    fn test_reorg_to_competing_branch() {
+        let params = easy_params();
+        let main = mine_chain(3, &params);
+        let mut fork = main[..1].to_vec();
+        for _ in 0..3 {
+            let block = mine_on(&fork, &params, "Fork");
+            fork.push(block);
+        }
+
+        let mut state = ChainState::new(params);
+        add_all(&mut state, main.clone());
+        assert!(state.add_block(fork[1].clone()).unwrap().is_none());
+        assert!(state.add_block(fork[2].clone()).unwrap().is_none());
+        assert_eq!(state.blocks(), &main[..]);
+
+        let reorg = state.add_block(fork[3].clone()).unwrap().unwrap();
+        assert_eq!(reorg.disconnected, main[1..].to_vec());
+        assert_eq!(reorg.connected, fork[1..].to_vec());
+        assert_eq!(state.blocks(), &fork[..]);
+        assert!(state.get(&main[2].hash).is_some());
+    }
+
+    #[test]
+    fn test_batch_reports_reorg_before_invalid_block() {
+        let params = easy_params();
+        let main = mine_chain(2, &params);
+        let mut fork = main[..1].to_vec();
+        for _ in 0..3 {
+            let block = mine_on(&fork, &params, "Fork");
+            fork.push(block);
+        }
+        let mut invalid = fork[3].clone();
+        invalid.timestamp = 0;
+        remine(&mut invalid);
+
+        let mut state = ChainState::new(params);
+        add_all(&mut state, main.clone());
+        let update = state.add_chain(vec![fork[1].clone(), fork[2].clone(), invalid]);
+        assert!(update.error.is_some());
+        let reorg = update.reorg.unwrap();
+        assert_eq!(reorg.disconnected, main[1..].to_vec());
+        assert_eq!(reorg.connected, fork[1..3].to_vec());
+        assert_eq!(state.blocks(), &fork[..3]);
+    }
+
+    #[test]
+    fn test_deep_fork_checked_across_retargets() {
+        let params = params();
+        let main = mine_chain(30, &params);
+        let mut fork = main[..20].to_vec();
+        for _ in 0..11 {
+            let block = mine_on(&fork, &params, "Fork");
+            fork.push(block);
+        }
+
+        let mut state = ChainState::new(params);
+        add_all(&mut state, main.clone());
+        for block in &fork[20..30] {
+            assert!(state.add_block(block.clone()).unwrap().is_none());
+        }
+        let reorg = state.add_block(fork[30].clone()).unwrap().unwrap();
+        assert_eq!(reorg.disconnected, main[20..].to_vec());
+        assert_eq!(state.blocks(), &fork[..]);
+        assert_eq!(Some(state.best_tip()), fork.last());
+    }
+
+    #[test]
+    fn test_fork_checked_against_window_only() {
+        let params = params();
+        let window = ancestor_window(&params);
+        let main = mine_chain(40, &params);
+        let mut fork = main[..10].to_vec();
+        for _ in 0..20 {
+            let block = mine_on(&fork, &params, "Fork");
+            fork.push(block);
+        }
+
+        let mut state = ChainState::new(params);
+        add_all(&mut state, main.clone());
+        for block in &fork[10..] {
+            assert!(state.add_block(block.clone()).unwrap().is_none());
+        }
+        for n in [3_usize, 12, 25, 29] {
+            let first = (n + 1).saturating_sub(window);
+            assert_eq!(state.ancestors_of(&fork[n].hash), fork[first..=n].to_vec());
+        }
+    }
+
+    #[test]
+    fn test_foreign_genesis_rejected() {
+        let params = easy_params();
+        let other = ChainParams {
+            chain_id: "car-ledger-other".to_string(),
+            ..params.clone()
+        };
+        let foreign = mine_chain(3, &other);
+
+        let mut state = ChainState::new(params.clone());
+        assert_eq!(state.blocks(), &[params.genesis_block()]);
+        assert!(state.add_block(foreign[0].clone()).is_err());
+        assert!(state.add_chain(foreign).error.is_some());
+        assert_eq!(state.blocks(), &[params.genesis_block()]);
+    }
+
+    #[test]
+    fn test_chain_reloaded_from_store() {
+        let params = easy_params();
+        let main = mine_chain(3, &params);
+        let mut fork = main[..2].to_vec();
+        for _ in 0..2 {
+            let block = mine_on(&fork, &params, "Fork");
+            fork.push(block);
+        }
+        let path = std::env::temp_dir().join(format!("chain-reload-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut state = ChainState::open(params.clone(), &path).unwrap();
+        add_all(&mut state, main.clone());
+        assert!(add_all(&mut state, fork.clone()).is_some());
+        drop(state);
+
+        let state = ChainState::open(params.clone(), &path).unwrap();
+        assert_eq!(state.blocks(), &fork[..]);
+        assert_eq!(state.work(), chain_work(&fork));
+
+        let other = ChainParams {
+            chain_id: "car-ledger-other".to_string(),
+            ..params
+        };
+        assert!(ChainState::open(other, &path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_locator_thins_out_towards_genesis() {
+        let params = easy_params();
+        let chain = mine_chain(40, &params);
+        let heights: Vec<u32> = locator(&chain)
+            .iter()
+            .map(|hash| chain.iter().find(|b| b.hash == *hash).unwrap().id)
+            .collect();
+        assert_eq!(
+            heights,
+            vec![39, 38, 37, 36, 35, 34, 33, 32, 31, 30, 28, 24, 16, 0]
+        );
+        assert_eq!(locator(&chain[..1]), vec![chain[0].hash]);
+        assert!(locator(&[]).is_empty());
+    }
+}
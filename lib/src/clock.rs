@@ -0,0 +1,27 @@
+use crate::unix_time;
+use std::sync::Arc;
+
+/// Source of the current time in seconds since the unix epoch. Nodes read
+/// the wall clock, the simulator runs them on a virtual one.
+#[derive(Clone)]
+pub struct Clock(Arc<dyn Fn() -> u64 + Send + Sync>);
+
+impl Clock {
+    pub fn system() -> Clock {
+        Clock(Arc::new(unix_time))
+    }
+
+    pub fn from_fn(now: impl Fn() -> u64 + Send + Sync + 'static) -> Clock {
+        Clock(Arc::new(now))
+    }
+
+    pub fn now(&self) -> u64 {
+        (self.0)()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::system()
+    }
+}
@@ -0,0 +1,223 @@
+use crate::datatypes::BlockchainError;
+use crate::networking::{parse_seeds, Overflow};
+use crate::ret_err;
+use std::env;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+/// Command line flags and the environment variables that set the same
+/// option. Flags win over variables.
+const OPTIONS: [(&str, &str); 12] = [
+    ("--listen", "BLOCKCHAIN_LISTEN"),
+    ("--bind", "BLOCKCHAIN_BIND"),
+    ("--multicast-group", "BLOCKCHAIN_MULTICAST_GROUP"),
+    ("--multicast-interface", "BLOCKCHAIN_MULTICAST_INTERFACE"),
+    ("--multicast-ttl", "BLOCKCHAIN_MULTICAST_TTL"),
+    ("--sync", "BLOCKCHAIN_SYNC"),
+    ("--seeds", "BLOCKCHAIN_SEEDS"),
+    ("--workers", "BLOCKCHAIN_WORKERS"),
+    ("--queue-limit", "BLOCKCHAIN_QUEUE_LIMIT"),
+    ("--overflow", "BLOCKCHAIN_OVERFLOW"),
+    ("--ban-time", "BLOCKCHAIN_BAN_TIME"),
+    ("--cluster-key", "BLOCKCHAIN_CLUSTER_KEY"),
+];
+
+/// Network endpoints shared by nodes and clients.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetConfig {
+    /// UDP address a node receives unicast messages on. Several nodes can
+    /// share a host by listening on different ports.
+    pub listen: SocketAddr,
+    /// Local address messages are sent from, replies to a client come back
+    /// to it.
+    pub bind: SocketAddr,
+    /// Group messages for every node are sent to.
+    pub multicast_group: SocketAddrV4,
+    /// Interface multicast is sent and received on, unspecified lets the
+    /// system pick.
+    pub multicast_interface: Ipv4Addr,
+    /// Number of routers a multicast datagram may cross.
+    pub multicast_ttl: u32,
+    /// TCP address a node serves sync requests on.
+    pub sync: SocketAddr,
+    /// Nodes always contacted by unicast.
+    pub seeds: Vec<SocketAddr>,
+    /// Threads handling received messages.
+    pub workers: usize,
+    /// Received messages each queue between the socket and the node holds,
+    /// at most.
+    pub queue_limit: usize,
+    /// What happens to messages arriving while the node's inbox is full.
+    pub overflow: Overflow,
+    /// Seconds a misbehaving peer is ignored for.
+    pub ban_time: u64,
+    /// Secret shared by the cluster, messages are authenticated with it when
+    /// set.
+    pub cluster_key: Option<String>,
+}
+
+impl Default for NetConfig {
+    fn default() -> NetConfig {
+        NetConfig {
+            listen: SocketAddr::from(([0, 0, 0, 0], 9000)),
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            multicast_group: SocketAddrV4::new(Ipv4Addr::new(239, 0, 0, 1), 9000),
+            multicast_interface: Ipv4Addr::UNSPECIFIED,
+            multicast_ttl: 1,
+            sync: SocketAddr::from(([0, 0, 0, 0], 9001)),
+            seeds: Vec::new(),
+            workers: 4,
+            queue_limit: 1024,
+            overflow: Overflow::DropNewest,
+            ban_time: 24 * 60 * 60,
+            cluster_key: None,
+        }
+    }
+}
+
+impl NetConfig {
+    /// Defaults overridden by the environment, then by the flags in `args`.
+    /// Returns the configuration and the arguments that aren't flags.
+    pub fn load(args: &[String]) -> Result<(NetConfig, Vec<String>), Box<dyn std::error::Error>> {
+        NetConfig::from_sources(|var| env::var(var).ok(), args)
+    }
+
+    fn from_sources(
+        var: impl Fn(&str) -> Option<String>,
+        args: &[String],
+    ) -> Result<(NetConfig, Vec<String>), Box<dyn std::error::Error>> {
+        let mut config = NetConfig::default();
+        for (flag, name) in OPTIONS {
+            if let Some(value) = var(name) {
+                config.set(flag, &value)?;
+            }
+        }
+        let mut rest = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                rest.push(arg.clone());
+                continue;
+            }
+            match arg.split_once('=') {
+                Some((flag, value)) => config.set(flag, value)?,
+                None => match args.next() {
+                    Some(value) => config.set(arg, value)?,
+                    None => {
+                        ret_err!(format!("Missing value for {arg}."));
+                    }
+                },
+            }
+        }
+        Ok((config, rest))
+    }
+
+    fn set(&mut self, flag: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let invalid = |e: std::net::AddrParseError| format!("Invalid {flag} {value}: {e}");
+        match flag {
+            "--listen" => self.listen = value.parse().map_err(invalid)?,
+            "--bind" => self.bind = value.parse().map_err(invalid)?,
+            "--multicast-group" => {
+                self.multicast_group = value.parse().map_err(invalid)?;
+                if !self.multicast_group.ip().is_multicast() {
+                    ret_err!(format!("{value} isn't a multicast address."));
+                }
+            }
+            "--multicast-interface" => self.multicast_interface = value.parse().map_err(invalid)?,
+            "--multicast-ttl" => {
+                self.multicast_ttl = value
+                    .parse()
+                    .map_err(|e| format!("Invalid {flag} {value}: {e}"))?
+            }
+            "--sync" => self.sync = value.parse().map_err(invalid)?,
+            "--seeds" => self.seeds = parse_seeds(value),
+            "--workers" | "--queue-limit" => {
+                let count: usize = value
+                    .parse()
+                    .map_err(|e| format!("Invalid {flag} {value}: {e}"))?;
+                if count == 0 {
+                    ret_err!(format!("{flag} must be at least 1."));
+                }
+                match flag {
+                    "--workers" => self.workers = count,
+                    _ => self.queue_limit = count,
+                }
+            }
+            "--overflow" => self.overflow = value.parse()?,
+            "--ban-time" => {
+                self.ban_time = value
+                    .parse()
+                    .map_err(|e| format!("Invalid {flag} {value}: {e}"))?
+            }
+            "--cluster-key" => {
+                self.cluster_key = Some(value.to_string()).filter(|key| !key.is_empty())
+            }
+            _ => {
+                ret_err!(format!("Unknown option {flag}."));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NetConfig;
+    use crate::networking::Overflow;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_flags_override_environment() {
+        let var = |name: &str| match name {
+            "BLOCKCHAIN_LISTEN" => Some("0.0.0.0:9100".to_string()),
+            "BLOCKCHAIN_MULTICAST_TTL" => Some("4".to_string()),
+            "BLOCKCHAIN_OVERFLOW" => Some("block".to_string()),
+            "BLOCKCHAIN_CLUSTER_KEY" => Some("secret".to_string()),
+            _ => None,
+        };
+        let (config, rest) = NetConfig::from_sources(
+            var,
+            &args(&[
+                "client",
+                "--listen",
+                "127.0.0.1:9200",
+                "VIN",
+                "--sync=0.0.0.0:9300",
+                "--workers",
+                "8",
+                "--ban-time=60",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(rest, args(&["client", "VIN"]));
+        assert_eq!(config.listen, SocketAddr::from(([127, 0, 0, 1], 9200)));
+        assert_eq!(config.sync, SocketAddr::from(([0, 0, 0, 0], 9300)));
+        assert_eq!(config.multicast_ttl, 4);
+        assert_eq!(config.multicast_interface, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.queue_limit, 1024);
+        assert_eq!(config.overflow, Overflow::Block);
+        assert_eq!(config.ban_time, 60);
+        assert_eq!(config.cluster_key.as_deref(), Some("secret"));
+
+        // An empty key turns authentication off.
+        let (config, _) = NetConfig::from_sources(var, &args(&["--cluster-key="])).unwrap();
+        assert_eq!(config.cluster_key, None);
+    }
+
+    #[test]
+    fn test_invalid_options_rejected() {
+        let none = |_: &str| None;
+        assert!(NetConfig::from_sources(none, &args(&["--listen", "nowhere"])).is_err());
+        assert!(
+            NetConfig::from_sources(none, &args(&["--multicast-group", "10.0.0.1:9000"])).is_err()
+        );
+        assert!(NetConfig::from_sources(none, &args(&["--colour", "blue"])).is_err());
+        assert!(NetConfig::from_sources(none, &args(&["--bind"])).is_err());
+        assert!(NetConfig::from_sources(none, &args(&["--workers", "0"])).is_err());
+        assert!(NetConfig::from_sources(none, &args(&["--overflow", "ignore"])).is_err());
+    }
+}
@@ -1,12 +1,59 @@
+use crate::params::ChainParams;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::net::SocketAddr;
 
 pub const HASH_LEN: usize = 32;
+/// Maximum number of entries a single block may carry.
+pub const MAX_BLOCK_ENTRIES: usize = 64;
+/// Maximum serialized size of all entries of a block, in bytes.
+pub const MAX_BLOCK_DATA_SIZE: usize = 16 * 1024;
+/// Number of times a new message may be relayed from node to node.
+pub const GOSSIP_TTL: u8 = 8;
 
 #[derive(Debug)]
 pub struct BlockchainError(pub String);
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+/// Hashes as hex strings in human readable formats like JSON, as plain bytes
+/// in bincode.
+mod hex_hash {
+    use super::HASH_LEN;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        hash: &[u8; HASH_LEN],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return hash.serialize(serializer);
+        }
+        let hex: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; HASH_LEN], D::Error> {
+        if !deserializer.is_human_readable() {
+            return <[u8; HASH_LEN]>::deserialize(deserializer);
+        }
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() != 2 * HASH_LEN {
+            return Err(D::Error::custom("hash must have 64 hex digits"));
+        }
+        let mut hash = [0; HASH_LEN];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            let digits = hex
+                .get(2 * i..2 * i + 2)
+                .ok_or_else(|| D::Error::custom("hash must be ASCII"))?;
+            *byte = u8::from_str_radix(digits, 16).map_err(D::Error::custom)?;
+        }
+        Ok(hash)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Hash)]
 pub struct Vin {
     wmi: String,
     vds: String,
@@ -22,11 +69,39 @@ pub struct Car {
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Block {
+    #[serde(with = "hex_hash")]
     pub hash: [u8; HASH_LEN],
     pub id: u32,
+    #[serde(with = "hex_hash")]
     pub prev_hash: [u8; HASH_LEN],
     pub nonce: u32,
-    pub data: BlockData,
+    pub bits: u32,
+    pub timestamp: u64,
+    /// Bumped whenever miners run out of `nonce` values.
+    pub extra_nonce: u64,
+    /// Root of the Merkle tree over `data`, the entries themselves are not
+    /// part of the proof of work preimage.
+    #[serde(with = "hex_hash")]
+    pub merkle_root: [u8; HASH_LEN],
+    pub data: Vec<BlockData>,
+    pub mined_by: String,
+}
+
+/// Every field of a `Block` except its entries, enough to check its proof of
+/// work and its place in the chain.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct BlockHeader {
+    #[serde(with = "hex_hash")]
+    pub hash: [u8; HASH_LEN],
+    pub id: u32,
+    #[serde(with = "hex_hash")]
+    pub prev_hash: [u8; HASH_LEN],
+    pub nonce: u32,
+    pub bits: u32,
+    pub timestamp: u64,
+    pub extra_nonce: u64,
+    #[serde(with = "hex_hash")]
+    pub merkle_root: [u8; HASH_LEN],
     pub mined_by: String,
 }
 
@@ -37,18 +112,28 @@ pub enum RevPolish {
     Arg,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct ContractResult {
     pub block_id: u32,
+    pub entry: u32,
     pub args: Vec<f64>,
     pub result: f64,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Clone)]
+/// Request to evaluate the contract stored as entry `entry` of block `block_id`.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ContractCall {
+    pub block_id: u32,
+    pub entry: u32,
+    pub args: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum BlockData {
     Contract(Vec<RevPolish>),
     Car(Car),
     ContractResult(ContractResult),
+    Genesis(ChainParams),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,16 +142,55 @@ pub enum Comm {
     DataToBlock,
     PrintChain,
     Broadcast,
-    Blockchain,
     EndMining,
     CalcContract,
+    /// Asks for the current record and history of the `Vin` in `data`.
+    QueryVin,
+    /// Answer to `QueryVin`, an `Option<VehicleHistory>`.
+    VinRecord,
+    /// A node's `TipAnnouncement`, peers with less work sync from it over TCP.
+    Tip,
+    /// Asks for the headers following the first known hash of the locator in
+    /// `data`, a `Vec<[u8; HASH_LEN]>` listed tip first.
+    GetHeaders,
+    /// Answer to `GetHeaders`, a `Vec<BlockHeader>`.
+    Headers,
+    /// Asks for the blocks with the hashes in `data`.
+    GetBlocks,
+    /// Answer to `GetBlocks`, a `Vec<Block>`.
+    Blocks,
+    /// Liveness check carrying the sender's `Hello`.
+    Ping,
+    /// Answer to `Ping`, carrying the sender's `Hello`.
+    Pong,
+    /// Asks a node for its peer table.
+    GetPeers,
+    /// Answer to `GetPeers`, a `Vec<PeerInfo>`.
+    Peers,
+    /// Asks a node for the counters of the messages it received.
+    GetTraffic,
+    /// Answer to `GetTraffic`, a `Vec<SourceStats>`.
+    Traffic,
+    /// Asks a node for the scores and bans of misbehaving peers.
+    GetBans,
+    /// Answer to `GetBans` and `ClearBans`, a `Vec<BanEntry>`.
+    Bans,
+    /// Lifts the ban of the host in `data`, an `Option<IpAddr>`, or of every
+    /// host if `None`.
+    ClearBans,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 
 pub struct Msg {
+    pub chain_id: String,
     pub command: Comm,
     pub data: Vec<u8>,
+    /// Number of times the message may still be relayed.
+    pub ttl: u8,
+    /// Address the message was received from, filled in by the listener.
+    #[serde(skip)]
+    pub origin: Option<SocketAddr>,
 }
 
 impl Car {
@@ -83,6 +207,34 @@ impl Car {
             vin_number: vin_number.unwrap_or(Vin::new(None, None, None)),
         }
     }
+
+    pub fn owner_name(&self) -> &str {
+        &self.owner_name
+    }
+
+    pub fn owner_surname(&self) -> &str {
+        &self.owner_surname
+    }
+
+    pub fn distance_traveled(&self) -> u32 {
+        self.distance_traveled
+    }
+
+    pub fn vin(&self) -> &Vin {
+        &self.vin_number
+    }
+}
+
+impl Msg {
+    pub fn new(chain_id: &str, command: Comm, data: Vec<u8>) -> Msg {
+        Msg {
+            chain_id: chain_id.to_string(),
+            command,
+            data,
+            ttl: GOSSIP_TTL,
+            origin: None,
+        }
+    }
 }
 
 impl Block {
@@ -92,10 +244,46 @@ impl Block {
             id: 0,
             prev_hash: [0; HASH_LEN],
             nonce: 0,
-            data: BlockData::Car(Car::new(None, None, None, None)),
+            bits: 0,
+            timestamp: 0,
+            extra_nonce: 0,
+            merkle_root: [0; HASH_LEN],
+            data: Vec::new(),
             mined_by: "".to_string(),
         }
     }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            hash: self.hash,
+            id: self.id,
+            prev_hash: self.prev_hash,
+            nonce: self.nonce,
+            bits: self.bits,
+            timestamp: self.timestamp,
+            extra_nonce: self.extra_nonce,
+            merkle_root: self.merkle_root,
+            mined_by: self.mined_by.clone(),
+        }
+    }
+}
+
+impl BlockHeader {
+    /// Block with this header and the entries `data`.
+    pub fn into_block(self, data: Vec<BlockData>) -> Block {
+        Block {
+            hash: self.hash,
+            id: self.id,
+            prev_hash: self.prev_hash,
+            nonce: self.nonce,
+            bits: self.bits,
+            timestamp: self.timestamp,
+            extra_nonce: self.extra_nonce,
+            merkle_root: self.merkle_root,
+            data,
+            mined_by: self.mined_by,
+        }
+    }
 }
 
 impl Vin {
@@ -106,18 +294,61 @@ impl Vin {
             vis: vis.unwrap_or("".to_string()),
         }
     }
+
+    /// Splits a 17 character VIN into its three sections.
+    pub fn parse(vin: &str) -> Option<Vin> {
+        if vin.len() != 17 || !vin.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        let vin = vin.to_uppercase();
+        Some(Vin::new(
+            Some(vin[..3].to_string()),
+            Some(vin[3..9].to_string()),
+            Some(vin[9..].to_string()),
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wmi.is_empty() && self.vds.is_empty() && self.vis.is_empty()
+    }
+
+    /// The same VIN in upper case, as `parse` returns it.
+    pub fn to_canonical(&self) -> Vin {
+        Vin {
+            wmi: self.wmi.to_uppercase(),
+            vds: self.vds.to_uppercase(),
+            vis: self.vis.to_uppercase(),
+        }
+    }
+}
+
+impl fmt::Display for Vin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.wmi, self.vds, self.vis)
+    }
+}
+
+impl fmt::Display for Car {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Car owner: {} {}, distance: {}, VIN: {}",
+            self.owner_name, self.owner_surname, self.distance_traveled, self.vin_number
+        )
+    }
 }
 
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
             f,
-            "Block [ID: {} Hash: {} Prev Hash: {} Miner: {} Data: {}]",
+            "Block [ID: {} Hash: {} Prev Hash: {} Time: {} Miner: {} Data: {}]",
             self.id,
             format_hash(self.hash),
             format_hash(self.prev_hash),
+            self.timestamp,
             self.mined_by,
-            self.data,
+            format_data(&self.data),
         )
     }
 }
@@ -126,13 +357,16 @@ impl fmt::Debug for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
             f,
-            "Block [ID: {} Hash: {} Prev Hash: {} Miner: {} Nonce: {} Data: {}]",
+            "Block [ID: {} Hash: {} Prev Hash: {} Time: {} Miner: {} Nonce: {}/{} Bits: {:#010x} Data: {}]",
             self.id,
             format_hash(self.hash),
             format_hash(self.prev_hash),
+            self.timestamp,
             self.mined_by,
+            self.extra_nonce,
             self.nonce,
-            self.data
+            self.bits,
+            format_data(&self.data)
         )
     }
 }
@@ -152,19 +386,27 @@ impl fmt::Display for BlockData {
                 write!(f, "Contract: {:?}", s)
             }
             BlockData::Car(s) => {
-                write!(f, "Car owner: {} {}", s.owner_name, s.owner_surname)
+                write!(f, "{s}")
             }
             BlockData::ContractResult(s) => {
                 write!(
                     f,
-                    "Contract ID: {}, result: {}, args: {:?}",
-                    s.block_id, s.result, s.args
+                    "Contract ID: {}/{}, result: {}, args: {:?}",
+                    s.block_id, s.entry, s.result, s.args
                 )
             }
+            BlockData::Genesis(s) => {
+                write!(f, "Genesis of chain: {}", s.chain_id)
+            }
         }
     }
 }
 
+fn format_data(data: &[BlockData]) -> String {
+    let entries: Vec<String> = data.iter().map(|entry| entry.to_string()).collect();
+    format!("[{}]", entries.join("; "))
+}
+
 fn format_hash(hash: [u8; HASH_LEN]) -> String {
     let mut formatted = String::new();
     for i in &hash[0..8] {
@@ -0,0 +1,190 @@
+use crate::datatypes::{Block, HASH_LEN};
+use crate::params::ChainParams;
+
+/// Expands compact `bits` into a big-endian 256 bit target.
+///
+/// The top byte of `bits` is the length of the target in bytes, the lower
+/// three bytes are its most significant digits. Unlike Bitcoin there is no
+/// sign bit, so mantissas up to `0xffffff` are valid.
+pub fn bits_to_target(bits: u32) -> [u8; HASH_LEN] {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = (bits & 0x00ff_ffff).to_be_bytes();
+    let mut target = [0; HASH_LEN];
+
+    for (i, byte) in mantissa[1..].iter().enumerate() {
+        // Position of this mantissa byte counted from the least significant end.
+        let position = exponent as isize - 1 - i as isize;
+        if (0..HASH_LEN as isize).contains(&position) {
+            target[HASH_LEN - 1 - position as usize] = *byte;
+        }
+    }
+    target
+}
+
+/// Compresses a big-endian target into compact form, truncating it to the
+/// three most significant bytes.
+pub fn target_to_bits(target: &[u8; HASH_LEN]) -> u32 {
+    let first = match target.iter().position(|b| *b != 0) {
+        Some(s) => s,
+        None => return 0,
+    };
+    let exponent = (HASH_LEN - first) as u32;
+    let mut mantissa: u32 = 0;
+    for i in 0..3 {
+        mantissa <<= 8;
+        if first + i < HASH_LEN {
+            mantissa |= target[first + i] as u32;
+        }
+    }
+    (exponent << 24) | mantissa
+}
+
+/// Checks whether `hash` is strictly below the target encoded in `bits`.
+pub fn hash_meets_target(hash: &[u8; HASH_LEN], bits: u32) -> bool {
+    *hash < bits_to_target(bits)
+}
+
+/// Expected number of hashes needed to find a block with target `bits`.
+///
+/// Only the upper 128 bits of the target are taken into account, which is
+/// exact enough for any target easier than `2^128`.
+pub fn block_work(bits: u32) -> u128 {
+    let target = bits_to_target(bits);
+    let mut upper = [0; 16];
+    upper.copy_from_slice(&target[..16]);
+    let upper = u128::from_be_bytes(upper);
+    if upper == u128::MAX {
+        return 1;
+    }
+    u128::MAX / (upper + 1)
+}
+
+/// Multiplies the target in `bits` by `numerator / denominator`.
+fn scale_bits(bits: u32, numerator: u64, denominator: u64) -> u32 {
+    // Keep four extra bytes below the mantissa so small adjustments survive.
+    let mut exponent = (bits >> 24) as i64 - 4;
+    let mut mantissa = ((bits & 0x00ff_ffff) as u128) << 32;
+    mantissa = mantissa * numerator as u128 / denominator.max(1) as u128;
+
+    while mantissa > 0x00ff_ffff {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+    if mantissa == 0 || exponent < 0 {
+        return 0;
+    }
+    if exponent > HASH_LEN as i64 {
+        return ((HASH_LEN as u32) << 24) | 0x00ff_ffff;
+    }
+    target_to_bits(&bits_to_target(((exponent as u32) << 24) | mantissa as u32))
+}
+
+/// Returns the compact target the block following `chain` must carry.
+/// `chain` may be cut off below the last `retarget_interval` blocks, heights
+/// are taken from the block ids.
+///
+/// Every `retarget_interval` blocks the target is scaled by the ratio of the
+/// observed to the expected duration of the last period, limited to a factor
+/// of four in either direction and never easier than `pow_limit_bits`. The
+/// genesis block is left out of the first period, its timestamp is fixed
+/// long before the network started.
+pub fn next_bits(chain: &[Block], params: &ChainParams) -> u32 {
+    let last = match chain.last() {
+        Some(s) => s,
+        None => return params.initial_bits,
+    };
+    let interval = params.retarget_interval.max(2) as usize;
+    if !(last.id as usize + 1).is_multiple_of(interval) {
+        return last.bits;
+    }
+
+    let mut first = &chain[chain.len() - interval];
+    if first.id == 0 {
+        first = &chain[chain.len() - interval + 1];
+    }
+    let expected = params.target_block_time * (last.id - first.id) as u64;
+    if expected == 0 {
+        return last.bits;
+    }
+    let actual = last
+        .timestamp
+        .saturating_sub(first.timestamp)
+        .clamp(expected / 4, expected * 4);
+
+    let bits = scale_bits(last.bits, actual, expected);
+    if bits_to_target(bits) > bits_to_target(params.pow_limit_bits) {
+        return params.pow_limit_bits;
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bits_to_target, hash_meets_target, next_bits, target_to_bits};
+    use crate::datatypes::Block;
+    use crate::params::ChainParams;
+
+    #[test]
+    fn test_compact_round_trip() {
+        let target = bits_to_target(0x1d81_0000);
+        assert_eq!(target[..3], [0, 0, 0]);
+        assert_eq!(target[3], 0x81);
+        assert_eq!(target[4..], [0; 28]);
+        assert_eq!(target_to_bits(&target), 0x1d81_0000);
+
+        let mut hash = [0xff; 32];
+        hash[..3].copy_from_slice(&[0, 0, 0]);
+        hash[3] = 0x80;
+        assert!(hash_meets_target(&hash, 0x1d81_0000));
+        hash[3] = 0x81;
+        assert!(!hash_meets_target(&hash, 0x1d81_0000));
+    }
+
+    fn chain_with_spacing(params: &ChainParams, bits: u32, spacing: u64) -> Vec<Block> {
+        (0..params.retarget_interval)
+            .map(|i| {
+                let mut block = Block::new_empty();
+                block.id = i;
+                block.bits = bits;
+                block.timestamp = 1_000_000 + spacing * i as u64;
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = ChainParams::default();
+        let bits = params.initial_bits;
+        let on_time = chain_with_spacing(&params, bits, params.target_block_time);
+        assert_eq!(next_bits(&on_time, &params), params.initial_bits);
+        assert_eq!(next_bits(&on_time[..3], &params), params.initial_bits);
+
+        let slow = chain_with_spacing(&params, bits, params.target_block_time * 2);
+        assert_eq!(next_bits(&slow, &params), 0x1e01_0200);
+
+        let fast = chain_with_spacing(&params, bits, 0);
+        assert_eq!(next_bits(&fast, &params), 0x1d20_4000);
+
+        let test_params = ChainParams::test();
+        let slow = chain_with_spacing(&test_params, test_params.pow_limit_bits, 1000);
+        assert_eq!(next_bits(&slow, &test_params), test_params.pow_limit_bits);
+    }
+
+    #[test]
+    fn test_first_retarget_ignores_genesis_time() {
+        let params = ChainParams::default();
+        let mut chain = chain_with_spacing(&params, params.initial_bits, params.target_block_time);
+        chain[0].timestamp = 0;
+        assert_eq!(next_bits(&chain, &params), params.initial_bits);
+
+        // Past the first period, an old block in the window is taken as is
+        // and the target only eases by the factor four limit.
+        let mut later = chain_with_spacing(&params, params.initial_bits, params.target_block_time);
+        for block in later.iter_mut() {
+            block.id += params.retarget_interval;
+        }
+        later[0].timestamp = 0;
+        assert_eq!(next_bits(&later, &params), 0x1e02_0400);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::datatypes::BlockchainError;
+use crate::{ret_err, verify_broadcasted_block, Block, ChainState, ChainStore, ChainUpdate};
+use bincode::{deserialize, serialize};
+use std::fs;
+use std::path::Path;
+
+/// File formats a chain can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Pretty printed JSON, meant to be read by people.
+    Json,
+    /// Compact bincode, the same encoding blocks use on the wire.
+    Bincode,
+}
+
+impl ExportFormat {
+    /// JSON for files ending in `.json`, bincode for everything else.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ExportFormat {
+        match path.as_ref().extension() {
+            Some(s) if s.eq_ignore_ascii_case("json") => ExportFormat::Json,
+            _ => ExportFormat::Bincode,
+        }
+    }
+}
+
+pub fn encode_chain(
+    blocks: &[Block],
+    format: ExportFormat,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_vec_pretty(blocks)?),
+        ExportFormat::Bincode => Ok(serialize(blocks)?),
+    }
+}
+
+pub fn decode_chain(
+    bytes: &[u8],
+    format: ExportFormat,
+) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
+    match format {
+        ExportFormat::Json => Ok(serde_json::from_slice(bytes)?),
+        ExportFormat::Bincode => Ok(deserialize(bytes)?),
+    }
+}
+
+/// Writes the blocks from height `from` up to and including `to`, or the
+/// tip if `to` is `None`, to `path`. The format follows the file extension.
+/// Returns the number of exported blocks.
+pub fn export_chain<P: AsRef<Path>>(
+    store: &dyn ChainStore,
+    from: u32,
+    to: Option<u32>,
+    path: P,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let blocks = store.blocks();
+    let end = match to {
+        Some(s) => s as usize + 1,
+        None => blocks.len(),
+    };
+    if from as usize >= end || end > blocks.len() {
+        ret_err!(format!(
+            "Height range {from}..{end} is outside of the stored chain."
+        ));
+    }
+    let range = &blocks[from as usize..end];
+    fs::write(&path, encode_chain(range, ExportFormat::from_path(&path))?)?;
+    Ok(range.len())
+}
+
+/// Reads an export from `path` and adds it to `chain`.
+///
+/// The export may start at any height up to the tip of the active chain, the
+/// blocks below it are taken from there. Every block is checked with the
+/// same rules as a broadcasted chain before any of them is added, an export
+/// failing those checks is an error and leaves `chain` untouched.
+pub fn import_chain<P: AsRef<Path>>(
+    path: P,
+    chain: &mut ChainState,
+) -> Result<ChainUpdate, Box<dyn std::error::Error>> {
+    let imported = decode_chain(&fs::read(&path)?, ExportFormat::from_path(&path))?;
+    let start = match imported.first() {
+        Some(s) => s.id as usize,
+        None => {
+            ret_err!("Export holds no blocks.");
+        }
+    };
+    if start > chain.blocks().len() {
+        ret_err!("Export doesn't connect to the active chain.");
+    }
+
+    let mut full = chain.blocks()[..start].to_vec();
+    full.extend(imported.iter().cloned());
+    let now = chain.clock().now();
+    for (ctr, block) in imported.iter().enumerate() {
+        if block.id as usize != start + ctr {
+            ret_err!("Block id incorrect");
+        }
+        verify_broadcasted_block(block.clone(), &full, &chain.params, now)?;
+    }
+    Ok(chain.add_chain(imported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_chain, import_chain};
+    use crate::test_utils::{add_all, easy_params, mine_chain};
+    use crate::{ChainState, ChainStore, MemoryStore};
+    use std::path::PathBuf;
+
+    fn export_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{name}", std::process::id()))
+    }
+
+    fn store_of(len: usize) -> MemoryStore {
+        let mut store = MemoryStore::new();
+        for block in mine_chain(len, &easy_params()) {
+            store.append(block).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_round_trip_in_both_formats() {
+        let store = store_of(4);
+        for name in ["export.json", "export.bin"] {
+            let path = export_path(name);
+            assert_eq!(export_chain(&store, 0, None, &path).unwrap(), 4);
+            if name.ends_with(".json") {
+                let json = std::fs::read_to_string(&path).unwrap();
+                assert!(json.contains("Owner 1"));
+                let hash: String = store.blocks()[2]
+                    .hash
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect();
+                assert!(json.contains(&hash));
+            }
+
+            let mut chain = ChainState::new(easy_params());
+            let reorg = import_chain(&path, &mut chain).unwrap().reorg.unwrap();
+            assert_eq!(reorg.connected, store.blocks()[1..].to_vec());
+            assert_eq!(chain.blocks(), store.blocks());
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_range_export_extends_chain() {
+        let store = store_of(5);
+        let path = export_path("range.bin");
+        assert_eq!(export_chain(&store, 3, Some(4), &path).unwrap(), 2);
+        assert!(export_chain(&store, 3, Some(5), &path).is_err());
+
+        let mut chain = ChainState::new(easy_params());
+        assert!(import_chain(&path, &mut chain).is_err());
+        add_all(&mut chain, store.blocks()[1..3].to_vec());
+        assert!(import_chain(&path, &mut chain).unwrap().error.is_none());
+        assert_eq!(chain.blocks(), store.blocks());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tampered_export_rejected() {
+        let store = store_of(3);
+        let path = export_path("tampered.json");
+        export_chain(&store, 0, None, &path).unwrap();
+        let json = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("Owner 2", "Thief");
+        std::fs::write(&path, json).unwrap();
+
+        let mut chain = ChainState::new(easy_params());
+        assert!(import_chain(&path, &mut chain).is_err());
+        assert_eq!(chain.blocks().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,400 @@
+use crate::datatypes::{BlockchainError, HASH_LEN, MAX_BLOCK_DATA_SIZE};
+use crate::{ret_err, unix_time};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Version of the frame and message layout, frames of other versions are
+/// rejected.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Version, message id, fragment index, fragment count, message length and
+/// message checksum.
+const HEADER_LEN: usize = 1 + 8 + 2 + 2 + 4 + 4;
+
+/// Largest part of a message carried by a single datagram.
+pub const MAX_FRAGMENT_PAYLOAD: usize = 8 * 1024;
+
+/// Largest datagram the framing layer produces.
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_FRAGMENT_PAYLOAD;
+
+/// Largest message that can be sent. A block carries at most
+/// `MAX_BLOCK_DATA_SIZE` bytes of entries and replies stay well below this,
+/// whole chains are only exchanged over the TCP sync protocol.
+pub const MAX_MESSAGE_LEN: usize = 16 * MAX_BLOCK_DATA_SIZE;
+
+/// How long the fragments of an incomplete message are kept.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of messages being reassembled at the same time.
+const MAX_PARTIAL_MESSAGES: usize = 64;
+
+/// Maximum total length of the messages being reassembled.
+const MAX_PARTIAL_BYTES: usize = 16 * MAX_MESSAGE_LEN;
+
+/// Maximum number of messages being reassembled from a single host, so one
+/// sender can't keep every other one from getting large messages through.
+const MAX_PARTIAL_PER_SOURCE: usize = 4;
+
+/// Maximum total length of the messages being reassembled from one host.
+const MAX_PARTIAL_BYTES_PER_SOURCE: usize = 2 * MAX_MESSAGE_LEN;
+
+/// Source of message ids, seeded with the start time so a restarted node
+/// doesn't reuse the ids of its previous run.
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn next_message_id() -> u64 {
+    let _ = NEXT_MESSAGE_ID.compare_exchange(
+        0,
+        unix_time() << 20,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+    NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn checksum(message: &[u8]) -> [u8; 4] {
+    let sum: [u8; HASH_LEN] = Sha256::digest(message).into();
+    [sum[0], sum[1], sum[2], sum[3]]
+}
+
+/// Splits `message` into datagrams that each carry one fragment.
+pub fn encode_frames(message: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    if message.len() > MAX_MESSAGE_LEN {
+        ret_err!("Message is too large to be framed.");
+    }
+    let id = next_message_id();
+    let sum = checksum(message);
+    let chunks: Vec<&[u8]> = if message.is_empty() {
+        vec![&[]]
+    } else {
+        message.chunks(MAX_FRAGMENT_PAYLOAD).collect()
+    };
+
+    let count = chunks.len() as u16;
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len());
+            frame.push(PROTOCOL_VERSION);
+            frame.extend(id.to_be_bytes());
+            frame.extend((index as u16).to_be_bytes());
+            frame.extend(count.to_be_bytes());
+            frame.extend((message.len() as u32).to_be_bytes());
+            frame.extend(sum);
+            frame.extend(*chunk);
+            frame
+        })
+        .collect())
+}
+
+struct Header {
+    id: u64,
+    index: u16,
+    count: u16,
+    len: u32,
+    checksum: [u8; 4],
+}
+
+fn decode_header(frame: &[u8]) -> Result<Header, Box<dyn std::error::Error>> {
+    if frame.len() < HEADER_LEN {
+        ret_err!("Frame is shorter than its header.");
+    }
+    if frame[0] != PROTOCOL_VERSION {
+        ret_err!(format!("Unsupported protocol version {}.", frame[0]));
+    }
+    let header = Header {
+        id: u64::from_be_bytes(frame[1..9].try_into()?),
+        index: u16::from_be_bytes(frame[9..11].try_into()?),
+        count: u16::from_be_bytes(frame[11..13].try_into()?),
+        len: u32::from_be_bytes(frame[13..17].try_into()?),
+        checksum: frame[17..21].try_into()?,
+    };
+    if header.count == 0 || header.index >= header.count {
+        ret_err!("Fragment index is out of range.");
+    }
+    if header.len as usize > MAX_MESSAGE_LEN {
+        ret_err!("Message is larger than allowed.");
+    }
+    if header.count as usize != (header.len as usize).div_ceil(MAX_FRAGMENT_PAYLOAD).max(1) {
+        ret_err!("Message length doesn't fit its fragments.");
+    }
+    Ok(header)
+}
+
+/// Fragments received so far of one message.
+struct Partial {
+    len: u32,
+    checksum: [u8; 4],
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: Instant,
+}
+
+/// Puts messages back together from the frames of any number of senders.
+///
+/// Every message is either returned whole with a matching checksum or
+/// rejected with an error. Messages whose fragments don't all arrive within
+/// `REASSEMBLY_TIMEOUT` are dropped with a warning.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<(SocketAddr, u64), Partial>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Number of messages still missing fragments.
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Processes one datagram from `addr`. Returns the message it completes,
+    /// if any.
+    pub fn accept(
+        &mut self,
+        addr: SocketAddr,
+        frame: &[u8],
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        self.accept_at(addr, frame, Instant::now())
+    }
+
+    fn accept_at(
+        &mut self,
+        addr: SocketAddr,
+        frame: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        self.expire(now);
+        let header = decode_header(frame)?;
+        let payload = &frame[HEADER_LEN..];
+
+        if header.count == 1 {
+            return complete(header.len, header.checksum, payload.to_vec()).map(Some);
+        }
+        if payload.len() > MAX_FRAGMENT_PAYLOAD {
+            ret_err!("Fragment is larger than allowed.");
+        }
+
+        let key = (addr, header.id);
+        if !self.partial.contains_key(&key) {
+            self.admit(addr, header.len as usize)?;
+        }
+        let partial = self.partial.entry(key).or_insert_with(|| Partial {
+            len: header.len,
+            checksum: header.checksum,
+            fragments: vec![None; header.count as usize],
+            received: 0,
+            started: now,
+        });
+        if partial.fragments.len() != header.count as usize
+            || partial.len != header.len
+            || partial.checksum != header.checksum
+        {
+            self.partial.remove(&key);
+            ret_err!("Fragment headers of one message disagree.");
+        }
+        let slot = &mut partial.fragments[header.index as usize];
+        if slot.is_none() {
+            *slot = Some(payload.to_vec());
+            partial.received += 1;
+        }
+        if partial.received < partial.fragments.len() {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&key).unwrap();
+        let message: Vec<u8> = partial.fragments.into_iter().flatten().flatten().collect();
+        complete(partial.len, partial.checksum, message).map(Some)
+    }
+
+    /// Checks that a new message of `len` bytes from `addr` stays within the
+    /// reassembly limits, both overall and for its host.
+    fn admit(&self, addr: SocketAddr, len: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let mut bytes = len;
+        let mut source_messages = 0;
+        let mut source_bytes = len;
+        for ((from, _), partial) in &self.partial {
+            bytes += partial.len as usize;
+            if from.ip() == addr.ip() {
+                source_messages += 1;
+                source_bytes += partial.len as usize;
+            }
+        }
+        if source_messages >= MAX_PARTIAL_PER_SOURCE || source_bytes > MAX_PARTIAL_BYTES_PER_SOURCE
+        {
+            ret_err!(format!(
+                "Too many incomplete messages from {}, dropping fragment.",
+                addr.ip()
+            ));
+        }
+        if self.partial.len() >= MAX_PARTIAL_MESSAGES || bytes > MAX_PARTIAL_BYTES {
+            ret_err!("Too many incomplete messages, dropping fragment.");
+        }
+        Ok(())
+    }
+
+    /// Drops messages that have been incomplete for too long.
+    fn expire(&mut self, now: Instant) {
+        self.partial.retain(|(addr, id), partial| {
+            let alive = now.duration_since(partial.started) < REASSEMBLY_TIMEOUT;
+            if !alive {
+                warn!(
+                    "Dropping message {id} from {addr}: {} of {} fragments arrived",
+                    partial.received,
+                    partial.fragments.len()
+                );
+            }
+            alive
+        });
+    }
+}
+
+fn complete(
+    len: u32,
+    sum: [u8; 4],
+    message: Vec<u8>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if message.len() != len as usize {
+        ret_err!("Message length doesn't match its header.");
+    }
+    if checksum(&message) != sum {
+        ret_err!("Message checksum doesn't match.");
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode_frames, Reassembler, MAX_FRAGMENT_PAYLOAD, MAX_MESSAGE_LEN, MAX_PARTIAL_BYTES,
+        MAX_PARTIAL_BYTES_PER_SOURCE, MAX_PARTIAL_PER_SOURCE, REASSEMBLY_TIMEOUT,
+    };
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_fragments_reassembled_in_any_order() {
+        let sent = message(3 * MAX_FRAGMENT_PAYLOAD + 100);
+        let mut frames = encode_frames(&sent).unwrap();
+        assert_eq!(frames.len(), 4);
+        frames.swap(0, 3);
+        frames.insert(2, frames[1].clone());
+
+        let mut reassembler = Reassembler::new();
+        let last = frames.pop().unwrap();
+        for frame in &frames {
+            assert!(reassembler.accept(addr(1), frame).unwrap().is_none());
+        }
+        // The same message id from another sender is a different message.
+        assert!(reassembler.accept(addr(2), &last).unwrap().is_none());
+        assert_eq!(reassembler.accept(addr(1), &last).unwrap(), Some(sent));
+        assert_eq!(reassembler.pending(), 1);
+
+        let small = message(10);
+        let frame = &encode_frames(&small).unwrap()[0];
+        assert_eq!(reassembler.accept(addr(1), frame).unwrap(), Some(small));
+    }
+
+    #[test]
+    fn test_damaged_frames_rejected() {
+        let mut reassembler = Reassembler::new();
+        let mut frames = encode_frames(&message(2 * MAX_FRAGMENT_PAYLOAD)).unwrap();
+        frames[1][30] ^= 1;
+        assert!(reassembler.accept(addr(1), &frames[0]).unwrap().is_none());
+        assert!(reassembler.accept(addr(1), &frames[1]).is_err());
+
+        let mut frame = encode_frames(&message(10)).unwrap().remove(0);
+        frame[0] += 1;
+        assert!(reassembler.accept(addr(1), &frame).is_err());
+        assert!(reassembler.accept(addr(1), &frame[..5]).is_err());
+    }
+
+    /// First fragment of a `len` bytes message.
+    fn first_fragment(len: usize) -> Vec<u8> {
+        encode_frames(&message(len)).unwrap().remove(0)
+    }
+
+    fn host(host: u8, port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, host], port))
+    }
+
+    #[test]
+    fn test_oversized_messages_rejected() {
+        assert!(encode_frames(&message(MAX_MESSAGE_LEN + 1)).is_err());
+
+        // Claims a longer message than its fragments can carry.
+        let mut frame = first_fragment(2 * MAX_FRAGMENT_PAYLOAD);
+        frame[13..17].copy_from_slice(&(MAX_MESSAGE_LEN as u32 + 1).to_be_bytes());
+        assert!(Reassembler::new().accept(addr(1), &frame).is_err());
+
+        // Claims more fragments than its length needs.
+        let mut frame = first_fragment(2 * MAX_FRAGMENT_PAYLOAD);
+        frame[11..13].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(Reassembler::new().accept(addr(1), &frame).is_err());
+    }
+
+    #[test]
+    fn test_one_host_cannot_take_every_slot() {
+        let mut reassembler = Reassembler::new();
+        let frame = first_fragment(2 * MAX_FRAGMENT_PAYLOAD);
+        for port in 0..MAX_PARTIAL_PER_SOURCE as u16 {
+            assert!(reassembler.accept(host(1, port), &frame).unwrap().is_none());
+        }
+        // Another port of the same host doesn't get around the limit.
+        assert!(reassembler.accept(host(1, 9999), &frame).is_err());
+        assert!(reassembler.accept(host(2, 0), &frame).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reassembly_bytes_capped() {
+        let mut reassembler = Reassembler::new();
+        let frame = first_fragment(MAX_MESSAGE_LEN);
+        let per_source = MAX_PARTIAL_BYTES_PER_SOURCE / MAX_MESSAGE_LEN;
+        for port in 0..per_source as u16 {
+            assert!(reassembler.accept(host(1, port), &frame).unwrap().is_none());
+        }
+        assert!(reassembler.accept(host(1, 9999), &frame).is_err());
+
+        for i in per_source..MAX_PARTIAL_BYTES / MAX_MESSAGE_LEN {
+            let from = host(2 + (i / per_source) as u8, i as u16);
+            assert!(reassembler.accept(from, &frame).unwrap().is_none());
+        }
+        assert!(reassembler.accept(host(200, 0), &frame).is_err());
+        // Small messages still get through while the budget is used up.
+        let small = encode_frames(&message(10)).unwrap().remove(0);
+        assert!(reassembler.accept(host(200, 0), &small).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_incomplete_messages_expire() {
+        let mut reassembler = Reassembler::new();
+        let frames = encode_frames(&message(2 * MAX_FRAGMENT_PAYLOAD)).unwrap();
+        let start = Instant::now();
+        assert!(reassembler
+            .accept_at(addr(1), &frames[0], start)
+            .unwrap()
+            .is_none());
+
+        let later = start + REASSEMBLY_TIMEOUT;
+        assert!(reassembler
+            .accept_at(addr(1), &frames[1], later)
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.pending(), 1);
+    }
+}
@@ -1,91 +1,290 @@
+use log::debug;
 use log::info;
-use log::{debug};
+use log::warn;
 
-use crate::datatypes::{BlockData, BlockchainError, ContractResult};
-use crate::verify_new_block;
+use crate::bans::{Misbehaviour, Offence};
+use crate::chain::ChainUpdate;
+use crate::datatypes::{BlockData, BlockchainError, ContractCall, ContractResult, HASH_LEN};
+use crate::networking::{Endpoint, Hello};
+use crate::ret_err;
+use crate::reverse_polish;
+use crate::sync::{headers_after, tip_announcement};
 use crate::Block;
+use crate::ChainState;
+use crate::ChainStore;
 use crate::Comm;
+use crate::Mempool;
+use crate::Miner;
 use crate::Msg;
-use crate::{ret_err};
-use crate::{reverse_polish, verify_broadcasted_block};
+use crate::NodeState;
+use crate::Reorg;
+use crate::TipAnnouncement;
+use crate::Vin;
 use bincode::deserialize;
 use bincode::serialize;
-use crossbeam_channel::Sender;
+use std::net::{IpAddr, SocketAddr};
 
+/// Adds a block received from a peer or from our own miner and relays it
+/// once it is connected to the tree. Blocks we mined leave with the full
+/// TTL, blocks from peers with one hop less, until the TTL runs out.
 pub fn handle_new_block(
     msg: &Msg,
-    blockchain: &mut Vec<Block>,
-    tx: &Sender<Msg>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    chain: &mut ChainState,
+    node: &NodeState,
+) -> Result<Option<Reorg>, Box<dyn std::error::Error>> {
     let block = deserialize::<Block>(&msg.data)?;
-    if (block.id as usize) != blockchain.len() {
-        debug!("Block ID didn't match!");
-        return Ok(());
+    if chain.contains(&block.hash) {
+        debug!("Block already known!");
+        return Ok(None);
     };
-    verify_new_block(block.clone(), blockchain)?;
+    let reorg = chain
+        .add_block_from(block.clone(), msg.origin.map(|addr| addr.ip()))
+        .map_err(|e| Misbehaviour::new(Offence::InvalidBlock, e))?;
+    if reorg.is_none() {
+        debug!("Block stored without changing the active chain: {block}");
+    }
+    if chain.get(&block.hash).is_none() {
+        debug!("Not relaying orphan block {block}");
+        return Ok(reorg);
+    }
+    match msg.origin {
+        None => node.endpoint.send_to_peers(msg, &node.peers)?,
+        Some(_) if msg.ttl == 0 => debug!("Block reached the end of its TTL"),
+        Some(from) => {
+            let relayed = Msg {
+                ttl: msg.ttl - 1,
+                ..Msg::new(&msg.chain_id, Comm::NewBlock, msg.data.clone())
+            };
+            node.endpoint.relay(&relayed, &node.peers, from)?;
+        }
+    }
+    Ok(reorg)
+}
 
-    tx.send(Msg {
-        command: Comm::EndMining,
-        data: Vec::new(),
-    })?;
-    info!("Adding new block: {block}");
-    blockchain.push(block);
-    Ok(())
+/// Adds a batch of blocks downloaded by the syncer.
+pub fn handle_blocks(msg: &Msg, chain: &mut ChainState) -> ChainUpdate {
+    match deserialize::<Vec<Block>>(&msg.data) {
+        Ok(s) => {
+            let mut update = chain.add_chain(s);
+            if let Some(e) = update.error.take() {
+                update.error = Some(Box::new(Misbehaviour::new(Offence::InvalidBlock, e)));
+            }
+            update
+        }
+        Err(e) => ChainUpdate {
+            reorg: None,
+            error: Some(e),
+        },
+    }
 }
 
-pub fn handle_incoming_blockchain(
+/// Updates the mempool and the miner after the active chain changed.
+pub fn handle_reorg(reorg: &Reorg, chain: &ChainState, mempool: &mut Mempool, miner: &mut Miner) {
+    miner.stop();
+    mempool.apply_reorg(reorg);
+    if !reorg.disconnected.is_empty() {
+        info!(
+            "Reorganisation: {} blocks disconnected, {} blocks connected",
+            reorg.disconnected.len(),
+            reorg.connected.len()
+        );
+    }
+    info!("New best tip: {}", chain.best_tip());
+}
+
+/// Starts syncing from the announcing node if its chain carries more work
+/// than ours. A finished download that fell short of its announced work
+/// gets its peer penalized first.
+pub fn handle_tip(
     msg: &Msg,
-    current_blockchain: &Vec<Block>,
-) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
-    let new_blockchain = deserialize::<Vec<Block>>(&msg.data)?;
-    if current_blockchain.len() >= new_blockchain.len() {
-        ret_err!("New block is shorter or equal in lenght to current one.");
+    chain: &ChainState,
+    node: &mut NodeState,
+    tx: &crossbeam_channel::Sender<Msg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let now = node.clock.now();
+    if let Some(peer) = node.syncer.collect(now) {
+        warn!("{peer} delivered less work than it announced");
+        node.penalize_host(peer.ip(), Offence::FalseTip);
     }
-    let mut ctr: u32 = 0;
-    for block in &new_blockchain {
-        if block.id != ctr {
-            ret_err!("Block id incorrect");
+    let tip = deserialize::<TipAnnouncement>(&msg.data)?;
+    if tip.work <= chain.work() || chain.contains(&tip.hash) {
+        return Ok(());
+    }
+    let origin = match msg.origin {
+        Some(s) => s,
+        None => {
+            ret_err!("Tip announcement has no sender address.");
         }
-        verify_broadcasted_block(block.clone(), &new_blockchain.as_ref())?;
-        ctr += 1;
+    };
+    let peer = SocketAddr::new(origin.ip(), tip.sync_port);
+    if node.syncer.start(peer, tip.work, chain, tx, now) {
+        info!("Syncing from {peer}, announced height {}", tip.height);
     }
-    return Ok(new_blockchain.clone());
+    Ok(())
 }
 
-pub fn handle_calc_contract(
+/// Sends our tip to a node asking for headers over multicast if we have
+/// blocks it lacks, the download itself then runs over TCP.
+pub fn handle_get_headers(
     msg: &Msg,
-    tx: &std::sync::mpsc::Sender<Msg>,
-    blockchain: &mut Vec<Block>,
+    chain: &ChainState,
+    node: &NodeState,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut args = deserialize::<Vec<f64>>(&msg.data)?;
-    let block_id: usize;
-    match args.pop() {
-        Some(s) => {
-            block_id = s as usize;
+    let locator = deserialize::<Vec<[u8; HASH_LEN]>>(&msg.data)?;
+    let origin = match msg.origin {
+        Some(s) => s,
+        None => {
+            ret_err!("Headers request has no sender address.");
         }
+    };
+    if headers_after(chain, &locator).is_empty() {
+        return Ok(());
+    }
+    node.endpoint
+        .send_to(&tip_announcement(chain, node)?, origin)
+}
+
+/// Records the sender of a `Ping` or `Pong`, and answers pings.
+pub fn handle_ping(msg: &Msg, node: &mut NodeState) -> Result<(), Box<dyn std::error::Error>> {
+    let hello = deserialize::<Hello>(&msg.data)?;
+    let origin = match msg.origin {
+        Some(s) => s,
         None => {
-            ret_err!("Couldn't extract block id");
+            ret_err!("Ping has no sender address.");
         }
+    };
+    if hello == node.hello() {
+        // Our own multicast ping.
+        return Ok(());
     }
-    if block_id >= blockchain.len() {
-        ret_err!("Block id is bigger than blockchain lenght");
+    let addr = SocketAddr::new(origin.ip(), hello.listen_port);
+    node.peers.seen(addr, &hello.name, node.clock.now());
+    if let Comm::Ping = msg.command {
+        let reply = Msg::new(&msg.chain_id, Comm::Pong, serialize(&node.hello())?);
+        node.endpoint.send_to(&reply, addr)?;
     }
-    let block_data = &blockchain[block_id].data;
+    Ok(())
+}
+
+/// Sends our peer table back to whoever asked.
+pub fn handle_get_peers(msg: &Msg, node: &NodeState) -> Result<(), Box<dyn std::error::Error>> {
+    let origin = match msg.origin {
+        Some(s) => s,
+        None => {
+            ret_err!("Peers request has no sender address.");
+        }
+    };
+    let reply = Msg::new(&msg.chain_id, Comm::Peers, serialize(&node.peers.peers())?);
+    node.endpoint.send_to(&reply, origin)
+}
+
+/// Sends our per host traffic counters back to whoever asked.
+pub fn handle_get_traffic(msg: &Msg, node: &NodeState) -> Result<(), Box<dyn std::error::Error>> {
+    let origin = match msg.origin {
+        Some(s) => s,
+        None => {
+            ret_err!("Traffic request has no sender address.");
+        }
+    };
+    let sources = match node.traffic.lock() {
+        Ok(s) => s.sources(),
+        Err(_) => {
+            ret_err!("Traffic counters lock is poisoned.");
+        }
+    };
+    let reply = Msg::new(&msg.chain_id, Comm::Traffic, serialize(&sources)?);
+    node.endpoint.send_to(&reply, origin)
+}
+
+/// Clears bans if asked to, then sends the ban list back to whoever asked.
+///
+/// Bans are only cleared for the local host, or for any holder of the
+/// cluster key when one is configured.
+pub fn handle_bans(msg: &Msg, node: &NodeState) -> Result<(), Box<dyn std::error::Error>> {
+    let origin = match msg.origin {
+        Some(s) => s,
+        None => {
+            ret_err!("Bans request has no sender address.");
+        }
+    };
+    if let Comm::ClearBans = msg.command {
+        if node.net.cluster_key.is_none() && !origin.ip().is_loopback() {
+            ret_err!(format!("{origin} isn't allowed to clear bans."));
+        }
+    }
+    let entries = match node.bans.lock() {
+        Ok(mut s) => {
+            if let Comm::ClearBans = msg.command {
+                let addr = deserialize::<Option<IpAddr>>(&msg.data)?;
+                let cleared = s.clear(addr);
+                info!("{origin} cleared {cleared} ban entries");
+            }
+            s.entries()
+        }
+        Err(_) => {
+            ret_err!("Ban list lock is poisoned.");
+        }
+    };
+    let reply = Msg::new(&msg.chain_id, Comm::Bans, serialize(&entries)?);
+    node.endpoint.send_to(&reply, origin)
+}
+
+/// Sends the record of the queried vehicle back to whoever asked.
+pub fn handle_query_vin(
+    msg: &Msg,
+    chain: &ChainState,
+    endpoint: &Endpoint,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let vin = deserialize::<Vin>(&msg.data)?;
+    let origin = match msg.origin {
+        Some(s) => s,
+        None => {
+            ret_err!("VIN query has no sender address.");
+        }
+    };
+    let reply = Msg::new(
+        &msg.chain_id,
+        Comm::VinRecord,
+        serialize(&chain.vins().get(&vin))?,
+    );
+    endpoint.send_to(&reply, origin)
+}
+
+pub fn handle_calc_contract(
+    msg: &Msg,
+    tx: &crossbeam_channel::Sender<Msg>,
+    store: &dyn ChainStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let call = deserialize::<ContractCall>(&msg.data)?;
+    let block = match store.get_by_height(call.block_id) {
+        Some(s) => s,
+        None => {
+            ret_err!("Block id is bigger than blockchain lenght");
+        }
+    };
+    let block_data = match block.data.get(call.entry as usize) {
+        Some(s) => s,
+        None => {
+            ret_err!("Block doesn't have an entry with this index.");
+        }
+    };
 
     match block_data {
         crate::BlockData::Contract(s) => {
             let data = BlockData::ContractResult(ContractResult {
-                block_id: (block_id as u32),
-                result: reverse_polish(s, &mut args.clone())?,
-                args,
+                block_id: call.block_id,
+                entry: call.entry,
+                result: reverse_polish(s, &call.args)?,
+                args: call.args,
             });
-            tx.send(Msg {
-                command: Comm::DataToBlock,
-                data: serialize(&data)?,
-            })?;
+            tx.send(Msg::new(
+                &msg.chain_id,
+                Comm::DataToBlock,
+                serialize(&data)?,
+            ))?;
         }
         _ => {
-            ret_err!("Provided block doesn't hold contract.");
+            ret_err!("Provided entry doesn't hold contract.");
         }
     }
 
@@ -1,20 +1,61 @@
+pub mod auth;
+pub mod bans;
+pub mod chain;
+pub mod clock;
+pub mod config;
 pub mod datatypes;
+pub mod difficulty;
+pub mod export;
+pub mod framing;
 mod handlers;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
 pub mod networking;
-pub use crate::datatypes::{Block, BlockData, Car, Comm, Msg, RevPolish, HASH_LEN};
-use crate::networking::{broadcast_chain, send_all};
-use bincode::deserialize;
-use bincode::serialize;
-use crossbeam_channel::unbounded;
-use crossbeam_channel::{Receiver, Sender};
+pub mod params;
+#[cfg(test)]
+mod sim;
+pub mod storage;
+pub mod sync;
+#[cfg(test)]
+mod test_utils;
+pub mod vin_index;
+pub use crate::auth::ClusterKey;
+use crate::bans::offence_of;
+pub use crate::bans::{BanEntry, BanList, Offence};
+pub use crate::chain::{ChainState, ChainUpdate, Reorg};
+pub use crate::clock::Clock;
+pub use crate::config::NetConfig;
+pub use crate::datatypes::{
+    Block, BlockData, BlockHeader, Car, Comm, ContractCall, Msg, RevPolish, Vin, HASH_LEN,
+};
+use crate::datatypes::{MAX_BLOCK_DATA_SIZE, MAX_BLOCK_ENTRIES};
+use crate::difficulty::{hash_meets_target, next_bits};
+pub use crate::export::{export_chain, import_chain, ExportFormat};
+pub use crate::mempool::Mempool;
+use crate::merkle::merkle_root;
+pub use crate::merkle::{merkle_proof, verify_merkle_proof, MerkleProof};
+pub use crate::miner::{Miner, MiningJobs, MiningThreads};
+use crate::networking::ping_peers;
+pub use crate::networking::{
+    Endpoint, Hello, MemoryHub, MemoryTransport, Overflow, PeerInfo, PeerTable, SourceStats,
+    TrafficStats, Transport, UdpTransport,
+};
+pub use crate::params::ChainParams;
+pub use crate::storage::{BlockLog, ChainStore, FileStore, MemoryStore};
+pub use crate::sync::{
+    SyncDownloads, SyncReport, SyncRequest, SyncThread, Syncer, TipAnnouncement,
+};
+pub use crate::vin_index::{VehicleHistory, VinIndex};
+use bincode::{deserialize, serialize};
 use datatypes::BlockchainError;
 use datatypes::RevPolish::{Arg, Number, Operation};
 use handlers::handle_calc_contract;
 use log::{debug, info, warn};
 use sha2::{Digest, Sha256};
-use std::sync::mpsc::Sender as StdSender;
-use std::thread;
-use std::thread::JoinHandle;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 #[macro_export]
 macro_rules! ret_err {
     ( $x:expr ) => {{
@@ -22,223 +63,345 @@ macro_rules! ret_err {
     }};
 }
 
-fn verify_block(block: Block) -> Result<Block, Box<dyn std::error::Error>> {
+/// Serializes every header field covered by the proof of work except the nonce.
+fn header_preimage(block: &Block) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut bytes: Vec<u8> = Vec::new();
-    bytes.extend(&block.id.to_be_bytes());
+    bytes.extend(block.id.to_be_bytes());
+    bytes.extend(block.prev_hash);
+    bytes.extend(block.bits.to_be_bytes());
+    bytes.extend(block.timestamp.to_be_bytes());
+    bytes.extend(block.extra_nonce.to_be_bytes());
+    bytes.extend(block.merkle_root);
+    bytes.extend(&serialize(&block.mined_by)?);
+    Ok(bytes)
+}
 
-    bytes.extend(&block.prev_hash);
-    bytes.extend(&serialize(&block.data).unwrap());
-    bytes.extend(&serialize(&block.mined_by).unwrap());
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(s) => s.as_secs(),
+        Err(_) => 0,
+    }
+}
 
+/// Recomputes the proof of work hash of `block` from its header fields.
+pub fn block_hash(block: &Block) -> Result<[u8; HASH_LEN], Box<dyn std::error::Error>> {
     let mut sha2_hash = Sha256::new();
-    sha2_hash.update(&bytes);
+    sha2_hash.update(header_preimage(block)?);
     sha2_hash.update(block.nonce.to_be_bytes());
-    let sum = sha2_hash.finalize();
+    Ok(sha2_hash.finalize().into())
+}
 
-    if (sum[0] == 0) && (sum[1] == 0) && (sum[2] == 0) && (sum[3] <= 128) {
-        return Ok(block);
+/// Checks the entries of `block` against the limits and its Merkle root.
+fn verify_entries(block: Block) -> Result<Block, Box<dyn std::error::Error>> {
+    if block.data.is_empty() {
+        ret_err!("Block carries no entries.");
+    }
+    if block.data.len() > MAX_BLOCK_ENTRIES {
+        ret_err!("Block carries too many entries.");
+    }
+    if serialize(&block.data)?.len() > MAX_BLOCK_DATA_SIZE {
+        ret_err!("Block entries are too large.");
+    }
+    if merkle_root(&block.data) != block.merkle_root {
+        ret_err!("Merkle root doesn't match block entries.");
     }
-    ret_err!("Hash in improper form for this nonce.");
+    Ok(block)
+}
+
+/// The genesis block isn't mined, it has to match the configured one exactly.
+fn verify_genesis(block: Block, params: &ChainParams) -> Result<Block, Box<dyn std::error::Error>> {
+    if block != params.genesis_block() {
+        ret_err!("Genesis block doesn't match chain parameters.");
+    }
+    Ok(block)
+}
+
+/// Number of preceding blocks whose median timestamp a new block must exceed.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks of `chain`.
+pub fn median_time_past(chain: &[Block]) -> Option<u64> {
+    let span = &chain[chain.len().saturating_sub(MEDIAN_TIME_SPAN)..];
+    let mut timestamps: Vec<u64> = span.iter().map(|block| block.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied()
+}
+
+fn verify_timestamp(
+    block: &Block,
+    ancestors: &[Block],
+    params: &ChainParams,
+    now: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(median) = median_time_past(ancestors) {
+        if block.timestamp <= median {
+            ret_err!("Block timestamp isn't past the median of previous blocks.");
+        }
+    }
+    if block.timestamp > now.saturating_add(params.max_future_drift) {
+        ret_err!("Block timestamp is too far in the future.");
+    }
+    Ok(())
+}
+
+/// Number of blocks before a new one that its header is checked against.
+fn ancestor_window(params: &ChainParams) -> usize {
+    (params.retarget_interval.max(2) as usize).max(MEDIAN_TIME_SPAN)
+}
+
+/// Checks the header of `block` against the chain before it: linkage,
+/// difficulty, timestamp and proof of work. The entries aren't looked at.
+/// `ancestors` ends with the parent and holds at least the last
+/// `ancestor_window` blocks, or every block down to the genesis block.
+/// `now` is the time the timestamp may not run too far ahead of.
+fn verify_header(
+    block: &Block,
+    ancestors: &[Block],
+    params: &ChainParams,
+    now: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let parent = match ancestors.last() {
+        Some(s) => s,
+        None => {
+            ret_err!("Only the genesis block has no parent.");
+        }
+    };
+    if block.id != parent.id + 1 {
+        ret_err!("Block ID doesn't follow its parent.");
+    }
+    if parent.hash != block.prev_hash {
+        ret_err!("Previous hash don't match!");
+    }
+    if block.bits != next_bits(ancestors, params) {
+        ret_err!("Block target doesn't match expected difficulty.");
+    }
+    verify_timestamp(block, ancestors, params, now)?;
+
+    if block_hash(block)? != block.hash {
+        ret_err!("Stored hash doesn't match block header.");
+    }
+    if !hash_meets_target(&block.hash, block.bits) {
+        ret_err!("Hash in improper form for this nonce.");
+    }
+    Ok(())
 }
 
 fn verify_broadcasted_block(
     block: Block,
-    blockchain: &Vec<Block>,
+    blockchain: &[Block],
+    params: &ChainParams,
+    now: u64,
 ) -> Result<Block, Box<dyn std::error::Error>> {
     debug!("Verifying block: {block}");
 
-    let control_prev_hash: [u8; 32] = if (block.id == 0) || (blockchain.len() == 0) {
-        [0; HASH_LEN]
-    } else {
-        blockchain[((block.id - 1) as usize)].hash
-    };
-
-    if control_prev_hash != block.prev_hash {
-        ret_err!("Previous hash don't match!");
+    if (block.id as usize) > blockchain.len() {
+        ret_err!("Block ID is past the end of the blockchain.");
+    }
+    if block.id == 0 {
+        return verify_genesis(block, params);
     }
 
-    verify_block(block)
+    verify_header(&block, &blockchain[..block.id as usize], params, now)?;
+    verify_entries(block)
 }
 
 fn verify_new_block(
     block: Block,
-    blockchain: &Vec<Block>,
+    blockchain: &[Block],
+    params: &ChainParams,
+    now: u64,
 ) -> Result<Block, Box<dyn std::error::Error>> {
     debug!("Verifying block: {block}");
 
     if (block.id as usize) != blockchain.len() {
         ret_err!("Block ID don't match blockchain lenght.");
     }
-
-    let control_prev_hash: [u8; 32] = match blockchain.last() {
-        Some(s) => s.hash,
-        None => [0; HASH_LEN],
-    };
-
-    if control_prev_hash != block.prev_hash {
-        ret_err!("Previous hash don't match!");
+    if block.id == 0 {
+        return verify_genesis(block, params);
     }
 
-    verify_block(block)
+    verify_header(&block, blockchain, params, now)?;
+    verify_entries(block)
 }
 
-pub fn mint_block(
-    msg: &Msg,
-    last_block: Block,
-    node_name: &String,
-    tx: StdSender<Msg>,
-    rx: Receiver<Msg>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut new_block = Block {
-        hash: [0; HASH_LEN],
-        id: 0,
-        nonce: 0,
-        prev_hash: [0; HASH_LEN],
-        data: deserialize::<BlockData>(&msg.data)?,
-        mined_by: node_name.to_string(),
-    };
-
-    new_block.prev_hash = last_block.hash;
-    new_block.id = if new_block.prev_hash == [0; HASH_LEN] {
-        0
-    } else {
-        last_block.id + 1
-    };
-
-    let calculated = mine_block(&mut new_block, rx)?;
-    new_block.nonce = calculated.0;
-    new_block.hash = calculated.1;
-
-    tx.send(Msg {
-        command: Comm::NewBlock,
-        data: serialize(&new_block)?,
-    })?;
-
-    send_all(Msg {
-        command: Comm::NewBlock,
-        data: serialize(&new_block).unwrap(),
-    })?;
-    Ok(())
+/// Everything a node keeps besides its chain.
+pub struct NodeState {
+    /// Name the node signs its blocks and introduces itself with.
+    pub name: String,
+    pub net: NetConfig,
+    /// Transport every message of the node is sent from.
+    pub endpoint: Endpoint,
+    /// Key messages and sync sessions are sealed with. `listen` and
+    /// `serve_sync` share it, so one replay cache covers every channel.
+    pub key: Option<Arc<ClusterKey>>,
+    pub mempool: Mempool,
+    pub miner: Miner,
+    pub syncer: Syncer,
+    pub peers: PeerTable,
+    /// Counters of the messages received per host, shared with `listen`.
+    pub traffic: Arc<Mutex<TrafficStats>>,
+    /// Misbehaviour scores of peers, shared with `listen`.
+    pub bans: Arc<Mutex<BanList>>,
+    /// Time peers are seen, scored and timed out by.
+    pub clock: Clock,
 }
 
-fn mine_block(
-    new_block: &mut Block,
-    rx: Receiver<Msg>,
-) -> Result<(u32, [u8; HASH_LEN]), Box<dyn std::error::Error>> {
-    let mut bytes: Vec<u8> = Vec::new();
+impl NodeState {
+    pub fn new(
+        name: &str,
+        miner: Miner,
+        net: NetConfig,
+        transport: Arc<dyn Transport>,
+    ) -> NodeState {
+        let key = ClusterKey::from_config(&net);
+        NodeState {
+            name: name.to_string(),
+            endpoint: Endpoint::new(transport).with_key(key.clone()),
+            peers: PeerTable::with_seeds(&net.seeds),
+            mempool: Mempool::new(),
+            miner,
+            syncer: Syncer::new().with_key(key.clone()),
+            key,
+            traffic: Arc::new(Mutex::new(TrafficStats::new())),
+            bans: Arc::new(Mutex::new(BanList::new(net.ban_time))),
+            clock: Clock::system(),
+            net,
+        }
+    }
 
-    bytes.extend(&new_block.id.to_be_bytes());
-    bytes.extend(&new_block.prev_hash);
-    bytes.extend(&serialize(&new_block.data)?);
-    bytes.extend(&serialize(&new_block.mined_by)?);
-
-    let mut nonce: u32 = 0;
-
-    while rx.is_empty() {
-        let mut sha2_hash = Sha256::new();
-        sha2_hash.update(&bytes);
-        sha2_hash.update(nonce.to_be_bytes());
-        let sum = sha2_hash.finalize();
-        if (sum[0] == 0) && (sum[1] == 0) && (sum[2] == 0) && (sum[3] <= 128) {
-            let result = match sum.try_into() {
-                Err(cause) => panic!("Can't convert a result hash to a slice: {cause}"),
-                Ok(result) => result,
-            };
-            return Ok((nonce, result));
-        };
-        nonce += 1;
+    /// Adds to the ban score of the sender of `msg` if `error` was its
+    /// fault.
+    pub fn penalize(&self, msg: &Msg, error: &(dyn std::error::Error + 'static)) {
+        if let (Some(origin), Some(offence)) = (msg.origin, offence_of(error)) {
+            self.penalize_host(origin.ip(), offence);
+        }
     }
-    ret_err!("Mining stopped via message.");
-}
 
-fn start_miner_thread(
-    msg: Msg,
-    blocks: &Vec<Block>,
-    node_name: &String,
-    tx_mpsc: &std::sync::mpsc::Sender<Msg>,
-    rx_mpmc: &Receiver<Msg>,
-) -> Option<JoinHandle<()>> {
-    let last_block = match blocks.last() {
-        Some(s) => s.clone(),
-        None => Block::new_empty().clone(),
-    };
-    let node_name_clone = node_name.clone();
-    let tx_mpsc_clone = tx_mpsc.clone();
-    let rx_mpmc_clone = rx_mpmc.clone();
-
-    let miner_thread = Some(thread::spawn({
-        move || match mint_block(
-            &msg,
-            last_block,
-            &node_name_clone,
-            tx_mpsc_clone,
-            rx_mpmc_clone,
-        ) {
-            Ok(_) => {}
-            Err(e) => {
-                debug!("Error during minting: {e}");
+    /// Adds the penalty of `offence` to the ban score of `addr`.
+    pub fn penalize_host(&self, addr: IpAddr, offence: Offence) {
+        match self.bans.lock() {
+            Ok(mut s) => {
+                s.penalize(addr, offence, self.clock.now());
             }
+            Err(_) => warn!("Ban list lock is poisoned"),
+        }
+    }
+
+    /// How peers reach this node.
+    pub fn hello(&self) -> Hello {
+        Hello {
+            name: self.name.clone(),
+            listen_port: self.endpoint.local_addr().port(),
         }
-    }));
-    return miner_thread;
+    }
 }
 
 pub fn handle_msg(
     msg: Msg,
-    blockchain: &mut Vec<Block>,
-    is_miner_running: &mut bool,
-    miner_thread: &mut Option<JoinHandle<()>>,
-    node_name: &String,
-
-    tx_mpsc: &std::sync::mpsc::Sender<Msg>,
-    tx_mpmc: &mut Sender<Msg>,
-    rx_mpmc: &mut Receiver<Msg>,
+    chain: &mut ChainState,
+    node: &mut NodeState,
+    tx_mpsc: &crossbeam_channel::Sender<Msg>,
 ) {
     match msg.command {
-        Comm::DataToBlock => {
-            if *is_miner_running {
-                *is_miner_running = !(miner_thread.as_ref().unwrap().is_finished());
-                if *is_miner_running {
-                    return;
+        Comm::DataToBlock => match deserialize::<BlockData>(&msg.data) {
+            Ok(s) => {
+                if !node.mempool.add(s) {
+                    debug!("Entry already pending, ignoring it");
                 }
-                (*tx_mpmc, *rx_mpmc) = unbounded::<Msg>();
             }
-
-            *miner_thread = start_miner_thread(msg, blockchain, &node_name, &tx_mpsc, &rx_mpmc);
-
-            *is_miner_running = true;
-        }
+            Err(e) => {
+                warn!("Error deserializing block data: {e}");
+                node.penalize(&msg, &e);
+            }
+        },
         Comm::Broadcast => {
-            broadcast_chain(blockchain);
+            if let Err(e) = sync::announce_tip(chain, node) {
+                warn!("Error announcing tip: {e}");
+            }
+            let hello = node.hello();
+            ping_peers(
+                &node.endpoint,
+                &chain.params.chain_id,
+                &hello,
+                &mut node.peers,
+                node.clock.now(),
+            );
         }
+        Comm::Tip => match handlers::handle_tip(&msg, chain, node, tx_mpsc) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Error handling tip announcement: {e}");
+                node.penalize(&msg, e.as_ref());
+            }
+        },
+        Comm::GetHeaders => match handlers::handle_get_headers(&msg, chain, node) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Error handling headers request: {e}");
+                node.penalize(&msg, e.as_ref());
+            }
+        },
 
-        Comm::NewBlock => {
-            if let Err(e) = handlers::handle_new_block(&msg, blockchain, &tx_mpmc) {
+        Comm::NewBlock => match handlers::handle_new_block(&msg, chain, node) {
+            Ok(Some(reorg)) => {
+                handlers::handle_reorg(&reorg, chain, &mut node.mempool, &mut node.miner);
+            }
+            Ok(None) => {}
+            Err(e) => {
                 warn!("Error during new block handling: {e}");
+                if msg.origin.is_none() {
+                    // Our own block, the same entries may be mined again.
+                    node.miner.forget_job();
+                }
+                node.penalize(&msg, e.as_ref());
+            }
+        },
+        Comm::Blocks => {
+            let update = handlers::handle_blocks(&msg, chain);
+            if let Some(reorg) = &update.reorg {
+                handlers::handle_reorg(reorg, chain, &mut node.mempool, &mut node.miner);
+            }
+            if let Some(e) = update.error {
+                warn!("Error adding downloaded blocks: {e}");
+                node.penalize(&msg, e.as_ref());
             }
         }
         Comm::PrintChain => {
-            info!("Current blockchain status: \n{:#?}", blockchain);
+            info!("Current blockchain status: \n{:#?}", chain.blocks());
+            info!("Pending entries: {}", node.mempool.len());
         }
-        Comm::Blockchain => match handlers::handle_incoming_blockchain(&msg, &blockchain) {
-            Ok(s) => {
-                info!("Accepting new blockchain");
-                match tx_mpmc.send(Msg {
-                    command: Comm::EndMining,
-                    data: Vec::new(),
-                }) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        warn!("Error sending message to miner thread: {e}");
-                    }
-                }
-                *blockchain = s;
+        Comm::Ping | Comm::Pong => match handlers::handle_ping(&msg, node) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Error handling ping: {e}");
+                node.penalize(&msg, e.as_ref());
+            }
+        },
+        Comm::GetPeers => match handlers::handle_get_peers(&msg, node) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Error answering peers request: {e}");
+            }
+        },
+        Comm::GetTraffic => match handlers::handle_get_traffic(&msg, node) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Error answering traffic request: {e}");
+            }
+        },
+        Comm::GetBans | Comm::ClearBans => match handlers::handle_bans(&msg, node) {
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Error answering bans request: {e}");
             }
+        },
+        Comm::QueryVin => match handlers::handle_query_vin(&msg, chain, &node.endpoint) {
+            Ok(()) => {}
             Err(e) => {
-                debug!("New blockchain verification failed: {e}");
+                warn!("Error answering VIN query: {e}");
             }
         },
-        Comm::CalcContract => match handle_calc_contract(&msg, tx_mpsc, blockchain) {
+        Comm::CalcContract => match handle_calc_contract(&msg, tx_mpsc, chain.store()) {
             Ok(()) => {
                 info!("Calculated contract value");
             }
@@ -249,26 +412,30 @@ pub fn handle_msg(
 
         _ => {}
     }
+
+    if !node.miner.is_running() && !node.mempool.is_empty() {
+        let template = node.mempool.template();
+        if !template.is_empty() {
+            node.miner.start(template, chain, &node.name, tx_mpsc);
+        }
+    }
 }
 
 fn reverse_polish(
-    contract_orig: &Vec<RevPolish>,
-    args_orig: &Vec<f64>,
+    contract_orig: &[RevPolish],
+    args_orig: &[f64],
 ) -> Result<f64, Box<dyn std::error::Error>> {
     let mut parsed_ints: Vec<f64> = Vec::new();
-    let mut contract = contract_orig.clone();
-    let mut args = args_orig.clone();
+    let mut contract = contract_orig.to_vec();
+    let mut args = args_orig.to_vec();
 
     loop {
-        let value: RevPolish;
-        match contract.pop() {
-            Some(s) => {
-                value = s;
-            }
+        let value: RevPolish = match contract.pop() {
+            Some(s) => s,
             None => {
                 return Ok(parsed_ints.pop().unwrap());
             }
-        }
+        };
         match value {
             Number(n) => {
                 parsed_ints.push(n);
@@ -361,15 +528,134 @@ fn reverse_polish(
 mod tests {
     use std::vec;
 
+    use crate::datatypes::MAX_BLOCK_ENTRIES;
+    use crate::merkle::merkle_root;
+    use crate::test_utils::{easy_params, mine_at, mine_chain, mine_on, remine};
     use crate::{
         datatypes::RevPolish::Arg, datatypes::RevPolish::Number, datatypes::RevPolish::Operation,
-        reverse_polish,
+        median_time_past, reverse_polish, unix_time, verify_new_block, Block, BlockData, Car,
+        ChainState, HASH_LEN,
     };
 
+    /// Whether a node on a fresh chain refuses to make `blocks`, as
+    /// downloaded by its syncer, its active chain.
+    fn rejected(blocks: Vec<Block>) -> bool {
+        let mut chain = ChainState::new(easy_params());
+        let tip = blocks.last().unwrap().hash;
+        let update = chain.add_chain(blocks);
+        update.error.is_some() || chain.tip().hash != tip
+    }
+
+    #[test]
+    fn test_valid_blocks_accepted() {
+        let params = easy_params();
+        let chain = mine_chain(3, &params);
+        let next = mine_on(&chain, &params, "Next");
+        assert!(verify_new_block(next, &chain, &params, unix_time()).is_ok());
+        assert!(!rejected(chain));
+    }
+
+    #[test]
+    fn test_wrong_hash_rejected() {
+        let params = easy_params();
+        let chain = mine_chain(2, &params);
+        let mut forged = mine_on(&chain, &params, "Forged");
+        forged.hash = [0; HASH_LEN];
+        assert!(verify_new_block(forged, &chain, &params, unix_time()).is_err());
+
+        let mut forged_chain = chain.clone();
+        forged_chain[1].hash[HASH_LEN - 1] ^= 1;
+        assert!(rejected(forged_chain));
+    }
+
+    #[test]
+    fn test_wrong_prev_hash_rejected() {
+        let params = easy_params();
+        let chain = mine_chain(2, &params);
+        let mut relinked = mine_on(&chain[..1], &params, "Relinked");
+        relinked.prev_hash = [7; HASH_LEN];
+        remine(&mut relinked);
+
+        assert!(verify_new_block(relinked.clone(), &chain[..1], &params, unix_time()).is_err());
+        let forged_chain = vec![chain[0].clone(), relinked];
+        assert!(rejected(forged_chain));
+    }
+
+    #[test]
+    fn test_wrong_id_rejected() {
+        let params = easy_params();
+        let chain = mine_chain(2, &params);
+        let mut skipped = mine_on(&chain, &params, "Skipped");
+        skipped.id += 1;
+        remine(&mut skipped);
+        assert!(verify_new_block(skipped.clone(), &chain, &params, unix_time()).is_err());
+
+        let forged_chain = vec![chain[0].clone(), chain[1].clone(), skipped];
+        assert!(rejected(forged_chain));
+    }
+
+    #[test]
+    fn test_tampered_data_rejected() {
+        let params = easy_params();
+        let chain = mine_chain(3, &params);
+        let mut tampered = mine_on(&chain, &params, "Honest");
+        tampered.data[0] = BlockData::Car(Car::new(Some("Thief".to_string()), None, None, None));
+        assert!(verify_new_block(tampered, &chain, &params, unix_time()).is_err());
+
+        let mut forged_chain = chain.clone();
+        forged_chain[1].data[0] =
+            BlockData::Car(Car::new(Some("Thief".to_string()), None, None, None));
+        assert!(rejected(forged_chain));
+    }
+
+    #[test]
+    fn test_timestamps_validated() {
+        let params = easy_params();
+        let chain = mine_chain(12, &params);
+        let median = median_time_past(&chain).unwrap();
+        assert_eq!(median, chain[6].timestamp);
+
+        let stale = mine_at(&chain, &params, "Stale", median);
+        assert!(verify_new_block(stale, &chain, &params, unix_time()).is_err());
+        let fresh = mine_at(&chain, &params, "Fresh", median + 1);
+        assert!(verify_new_block(fresh, &chain, &params, unix_time()).is_ok());
+
+        let future = unix_time() + params.max_future_drift + 60;
+        let early = mine_at(&chain, &params, "Early", future);
+        assert!(verify_new_block(early.clone(), &chain, &params, unix_time()).is_err());
+
+        let mut forged_chain = chain.clone();
+        forged_chain.push(early);
+        assert!(rejected(forged_chain));
+    }
+
+    #[test]
+    fn test_entry_limits_enforced() {
+        let params = easy_params();
+        let chain = mine_chain(1, &params);
+        let car = BlockData::Car(Car::new(Some("Owner".to_string()), None, None, None));
+
+        let mut full = mine_on(&chain, &params, "Full");
+        full.data = vec![car.clone(); MAX_BLOCK_ENTRIES];
+        full.merkle_root = merkle_root(&full.data);
+        remine(&mut full);
+        assert!(verify_new_block(full.clone(), &chain, &params, unix_time()).is_ok());
+
+        full.data.push(car);
+        full.merkle_root = merkle_root(&full.data);
+        remine(&mut full);
+        assert!(verify_new_block(full.clone(), &chain, &params, unix_time()).is_err());
+
+        full.data.clear();
+        full.merkle_root = merkle_root(&full.data);
+        remine(&mut full);
+        assert!(verify_new_block(full, &chain, &params, unix_time()).is_err());
+    }
+
     #[test]
     fn test_rev_polish() {
         let mut input = vec![Operation('+'), Number(0.0), Number(1.0)];
-        assert_eq!(reverse_polish(&mut input, &mut Vec::new()).unwrap(), 1.0);
+        assert_eq!(reverse_polish(&input, &Vec::new()).unwrap(), 1.0);
         input = vec![
             Operation('*'),
             Number(2.0),
@@ -377,7 +663,7 @@ mod tests {
             Number(3.0),
             Number(5.0),
         ];
-        assert_eq!(reverse_polish(&mut input, &mut Vec::new()).unwrap(), 16.0);
+        assert_eq!(reverse_polish(&input, &Vec::new()).unwrap(), 16.0);
         input = vec![
             Operation('*'),
             Number(2.0),
@@ -385,11 +671,11 @@ mod tests {
             Number(3.0),
             Number(5.0),
         ];
-        assert_eq!(reverse_polish(&mut input, &mut Vec::new()).unwrap(), -4.0);
+        assert_eq!(reverse_polish(&input, &Vec::new()).unwrap(), -4.0);
 
-        let mut args: Vec<f64> = vec![5.0, 3.0];
+        let args: Vec<f64> = vec![5.0, 3.0];
         input = vec![Operation('*'), Number(2.0), Operation('-'), Arg, Arg];
 
-        assert_eq!(reverse_polish(&mut input, &mut args).unwrap(), -4.0);
+        assert_eq!(reverse_polish(&input, &args).unwrap(), -4.0);
     }
 }
@@ -0,0 +1,168 @@
+use crate::datatypes::{HASH_LEN, MAX_BLOCK_DATA_SIZE, MAX_BLOCK_ENTRIES};
+use crate::merkle::leaf_hash;
+use crate::{BlockData, Reorg};
+use bincode::serialize;
+use log::warn;
+use std::collections::VecDeque;
+
+/// Maximum number of entries waiting to be mined.
+const MAX_MEMPOOL_ENTRIES: usize = 1000;
+
+/// Serialized size of the length prefix bincode puts in front of a `Vec`.
+const VEC_PREFIX_SIZE: usize = 8;
+
+/// Entries waiting to be included in a block, oldest first.
+///
+/// Entries are identified by the hash of their content, so submitting the
+/// same data twice only queues it once.
+#[derive(Default)]
+pub struct Mempool {
+    entries: VecDeque<([u8; HASH_LEN], BlockData)>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, entry: &BlockData) -> bool {
+        let hash = leaf_hash(entry);
+        self.entries.iter().any(|(known, _)| *known == hash)
+    }
+
+    /// Queues `entry` unless it is already pending or too large to ever fit
+    /// into a block. Returns `true` if the entry was added.
+    pub fn add(&mut self, entry: BlockData) -> bool {
+        let hash = leaf_hash(&entry);
+        if self.entries.iter().any(|(known, _)| *known == hash) {
+            return false;
+        }
+        if VEC_PREFIX_SIZE.saturating_add(entry_size(&entry)) > MAX_BLOCK_DATA_SIZE {
+            warn!("Entry doesn't fit into a block, dropping it: {entry}");
+            return false;
+        }
+        if self.entries.len() >= MAX_MEMPOOL_ENTRIES {
+            warn!("Mempool full, dropping entry: {entry}");
+            return false;
+        }
+        self.entries.push_back((hash, entry));
+        true
+    }
+
+    /// Drops every pending entry that is part of `data`.
+    pub fn remove_included(&mut self, data: &[BlockData]) {
+        let included: Vec<[u8; HASH_LEN]> = data.iter().map(leaf_hash).collect();
+        self.entries.retain(|(hash, _)| !included.contains(hash));
+    }
+
+    /// Puts entries of disconnected blocks back into the pool and drops the
+    /// ones that the newly connected blocks include.
+    pub fn apply_reorg(&mut self, reorg: &Reorg) {
+        for block in &reorg.disconnected {
+            for entry in &block.data {
+                self.add(entry.clone());
+            }
+        }
+        for block in &reorg.connected {
+            self.remove_included(&block.data);
+        }
+    }
+
+    /// Oldest pending entries that fit into a single block. Entries too
+    /// large for the space left are skipped, later ones may still fit.
+    pub fn template(&self) -> Vec<BlockData> {
+        let mut size = VEC_PREFIX_SIZE;
+        let mut template = Vec::new();
+        for (_, entry) in &self.entries {
+            if template.len() == MAX_BLOCK_ENTRIES {
+                break;
+            }
+            let entry_size = entry_size(entry);
+            if size.saturating_add(entry_size) > MAX_BLOCK_DATA_SIZE {
+                continue;
+            }
+            size += entry_size;
+            template.push(entry.clone());
+        }
+        template
+    }
+}
+
+/// Serialized size of `entry` inside a block.
+fn entry_size(entry: &BlockData) -> usize {
+    serialize(entry).map_or(usize::MAX, |bytes| bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mempool;
+    use crate::datatypes::{MAX_BLOCK_DATA_SIZE, MAX_BLOCK_ENTRIES};
+    use crate::test_utils::{easy_params, mine_chain};
+    use crate::{BlockData, Car, Reorg};
+
+    fn car(owner: &str) -> BlockData {
+        BlockData::Car(Car::new(Some(owner.to_string()), None, None, None))
+    }
+
+    #[test]
+    fn test_duplicates_ignored() {
+        let mut mempool = Mempool::new();
+        assert!(mempool.add(car("James")));
+        assert!(!mempool.add(car("James")));
+        assert!(mempool.add(car("Oliver")));
+        assert_eq!(mempool.template(), vec![car("James"), car("Oliver")]);
+    }
+
+    #[test]
+    fn test_template_respects_entry_limit() {
+        let mut mempool = Mempool::new();
+        for i in 0..MAX_BLOCK_ENTRIES + 5 {
+            mempool.add(car(&format!("Owner {i}")));
+        }
+        assert_eq!(mempool.template().len(), MAX_BLOCK_ENTRIES);
+    }
+
+    #[test]
+    fn test_oversized_entry_rejected() {
+        let mut mempool = Mempool::new();
+        assert!(!mempool.add(car(&"x".repeat(MAX_BLOCK_DATA_SIZE))));
+        assert!(mempool.is_empty());
+
+        // Half-block entries only fit one at a time, the one behind them
+        // still makes it into the template.
+        let half = |i: usize| car(&format!("{i}{}", "x".repeat(MAX_BLOCK_DATA_SIZE / 2)));
+        assert!(mempool.add(half(1)));
+        assert!(mempool.add(half(2)));
+        assert!(mempool.add(car("James")));
+        assert_eq!(mempool.template(), vec![half(1), car("James")]);
+    }
+
+    #[test]
+    fn test_reorg_moves_entries() {
+        let chain = mine_chain(2, &easy_params());
+        let mut mempool = Mempool::new();
+        mempool.add(chain[1].data[0].clone());
+        mempool.add(car("Pending"));
+
+        mempool.apply_reorg(&Reorg {
+            disconnected: Vec::new(),
+            connected: vec![chain[1].clone()],
+        });
+        assert_eq!(mempool.template(), vec![car("Pending")]);
+
+        mempool.apply_reorg(&Reorg {
+            disconnected: vec![chain[1].clone()],
+            connected: vec![chain[0].clone()],
+        });
+        assert_eq!(mempool.len(), 2);
+        assert!(mempool.contains(&chain[1].data[0]));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::datatypes::HASH_LEN;
+use crate::{Block, BlockData};
+use bincode::serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Leaves and inner nodes are hashed with different prefixes, so an inner
+// node can never be passed off as an entry.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Position of a sibling hash relative to the node being proven.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Path of sibling hashes from one entry up to the Merkle root of a block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u32,
+    pub siblings: Vec<([u8; HASH_LEN], Side)>,
+}
+
+pub fn leaf_hash(entry: &BlockData) -> [u8; HASH_LEN] {
+    let mut sha2_hash = Sha256::new();
+    sha2_hash.update([LEAF_PREFIX]);
+    sha2_hash.update(serialize(entry).unwrap());
+    sha2_hash.finalize().into()
+}
+
+fn node_hash(left: &[u8; HASH_LEN], right: &[u8; HASH_LEN]) -> [u8; HASH_LEN] {
+    let mut sha2_hash = Sha256::new();
+    sha2_hash.update([NODE_PREFIX]);
+    sha2_hash.update(left);
+    sha2_hash.update(right);
+    sha2_hash.finalize().into()
+}
+
+/// Hashes one level of the tree into the next. A node without a sibling is
+/// carried up unchanged.
+fn next_level(level: &[[u8; HASH_LEN]]) -> Vec<[u8; HASH_LEN]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Merkle root over `entries`, all zeros for an empty list.
+pub fn merkle_root(entries: &[BlockData]) -> [u8; HASH_LEN] {
+    let mut level: Vec<[u8; HASH_LEN]> = entries.iter().map(leaf_hash).collect();
+    if level.is_empty() {
+        return [0; HASH_LEN];
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// Builds a proof that entry `index` of `block` is committed to by its
+/// `merkle_root`.
+pub fn merkle_proof(block: &Block, index: usize) -> Option<MerkleProof> {
+    if index >= block.data.len() {
+        return None;
+    }
+    let mut level: Vec<[u8; HASH_LEN]> = block.data.iter().map(leaf_hash).collect();
+    let mut position = index;
+    let mut siblings = Vec::new();
+
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if sibling < level.len() {
+            let side = if sibling < position {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            siblings.push((level[sibling], side));
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(MerkleProof {
+        index: index as u32,
+        siblings,
+    })
+}
+
+/// Checks that `entry` is included under `root` according to `proof`.
+pub fn verify_merkle_proof(entry: &BlockData, proof: &MerkleProof, root: &[u8; HASH_LEN]) -> bool {
+    let mut hash = leaf_hash(entry);
+    for (sibling, side) in &proof.siblings {
+        hash = match side {
+            Side::Left => node_hash(sibling, &hash),
+            Side::Right => node_hash(&hash, sibling),
+        };
+    }
+    hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merkle_proof, merkle_root, verify_merkle_proof};
+    use crate::{Block, BlockData, Car};
+
+    fn cars(count: usize) -> Vec<BlockData> {
+        (0..count)
+            .map(|i| {
+                BlockData::Car(Car::new(
+                    Some(format!("Owner {i}")),
+                    None,
+                    Some(i as u32),
+                    None,
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_proofs_for_every_entry() {
+        for count in 1..8 {
+            let mut block = Block::new_empty();
+            block.data = cars(count);
+            block.merkle_root = merkle_root(&block.data);
+
+            for (index, entry) in block.data.iter().enumerate() {
+                let proof = merkle_proof(&block, index).unwrap();
+                assert!(verify_merkle_proof(entry, &proof, &block.merkle_root));
+            }
+            assert!(merkle_proof(&block, count).is_none());
+        }
+    }
+
+    #[test]
+    fn test_proof_rejects_other_entry() {
+        let mut block = Block::new_empty();
+        block.data = cars(5);
+        block.merkle_root = merkle_root(&block.data);
+
+        let proof = merkle_proof(&block, 2).unwrap();
+        assert!(!verify_merkle_proof(
+            &block.data[3],
+            &proof,
+            &block.merkle_root
+        ));
+        assert!(!verify_merkle_proof(
+            &cars(6)[5],
+            &proof,
+            &block.merkle_root
+        ));
+        assert_ne!(merkle_root(&cars(5)), merkle_root(&cars(4)));
+    }
+}
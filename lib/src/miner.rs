@@ -0,0 +1,442 @@
+use crate::datatypes::{BlockchainError, HASH_LEN};
+use crate::difficulty::{hash_meets_target, next_bits};
+use crate::merkle::merkle_root;
+use crate::{header_preimage, median_time_past, ret_err, Block, BlockData, ChainState, Comm, Msg};
+use bincode::serialize;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// Number of hashes a worker computes between checks for a stop request.
+const CHECK_INTERVAL: u64 = 4096;
+
+/// A nonce together with the hash it produces.
+type Solution = (u32, [u8; HASH_LEN]);
+
+/// Runs the blocks a `Miner` starts.
+///
+/// Nodes mine on a pool of threads, the simulator times the jobs on its
+/// virtual clock instead.
+pub trait MiningJobs: Send {
+    /// Starts mining `block`, which is sent to `tx` as a `NewBlock` of
+    /// `chain_id` once it is mined.
+    fn start(&mut self, block: Block, chain_id: &str, tx: &Sender<Msg>);
+
+    fn is_running(&self) -> bool;
+
+    /// Takes the outcome of the last job once it ended, `false` if its block
+    /// wasn't handed over.
+    fn finished(&mut self) -> Option<bool>;
+
+    /// Abandons the running job and waits for it to end.
+    fn stop(&mut self);
+}
+
+/// Mines every block on `threads` worker threads.
+pub struct MiningThreads {
+    /// Number of worker threads the nonce space is split across.
+    pub threads: usize,
+    /// Chain the current block is mined for.
+    chain_id: String,
+    /// Mining thread, it returns whether the block was mined and sent.
+    handle: Option<JoinHandle<bool>>,
+    tx: Sender<Msg>,
+    rx: Receiver<Msg>,
+}
+
+impl MiningThreads {
+    pub fn new(threads: usize) -> MiningThreads {
+        let (tx, rx) = unbounded::<Msg>();
+        MiningThreads {
+            threads: threads.max(1),
+            chain_id: String::new(),
+            handle: None,
+            tx,
+            rx,
+        }
+    }
+}
+
+impl MiningJobs for MiningThreads {
+    fn start(&mut self, block: Block, chain_id: &str, tx: &Sender<Msg>) {
+        (self.tx, self.rx) = unbounded::<Msg>();
+        self.chain_id = chain_id.to_string();
+        let chain_id = self.chain_id.clone();
+        let threads = self.threads;
+        let tx_mpsc = tx.clone();
+        let rx = self.rx.clone();
+
+        self.handle = Some(thread::spawn(move || {
+            match mint_block(block, &chain_id, threads, tx_mpsc, rx) {
+                Ok(_) => true,
+                Err(e) => {
+                    debug!("Error during minting: {e}");
+                    false
+                }
+            }
+        }));
+    }
+
+    fn is_running(&self) -> bool {
+        match &self.handle {
+            Some(s) => !s.is_finished(),
+            None => false,
+        }
+    }
+
+    fn finished(&mut self) -> Option<bool> {
+        let handle = self.handle.take_if(|s| s.is_finished())?;
+        Some(handle.join().unwrap_or(false))
+    }
+
+    fn stop(&mut self) {
+        if let Err(e) = self
+            .tx
+            .send(Msg::new(&self.chain_id, Comm::EndMining, Vec::new()))
+        {
+            warn!("Error sending message to miner thread: {e}");
+        }
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("Miner thread panicked");
+            }
+        }
+    }
+}
+
+/// Mines one block at a time on top of the active chain.
+pub struct Miner {
+    jobs: Box<dyn MiningJobs>,
+    /// Parent and Merkle root of the last block started. The same block
+    /// isn't started again until `stop` is called, as after a new tip, or
+    /// mining it failed.
+    last_job: Option<([u8; HASH_LEN], [u8; HASH_LEN])>,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            jobs: Box::new(MiningThreads::new(threads)),
+            last_job: None,
+        }
+    }
+
+    /// Uses one worker per available CPU core.
+    pub fn with_all_cores() -> Miner {
+        Miner::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// Hands the blocks to `jobs` instead of the mining threads.
+    pub fn with_jobs(mut self, jobs: Box<dyn MiningJobs>) -> Miner {
+        self.jobs = jobs;
+        self
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.jobs.is_running()
+    }
+
+    /// Starts mining `data` on top of the active chain. Returns `false` if a
+    /// block is already being mined, `data` is empty, as blocks must carry
+    /// entries, or the same entries were already mined on the same tip.
+    pub fn start(
+        &mut self,
+        data: Vec<BlockData>,
+        chain: &ChainState,
+        node_name: &str,
+        tx_mpsc: &Sender<Msg>,
+    ) -> bool {
+        if self.is_running() || data.is_empty() {
+            return false;
+        }
+        if self.jobs.finished() == Some(false) {
+            self.last_job = None;
+        }
+        // Our last block may be finished but not yet added to the chain.
+        let job = (chain.tip().hash, merkle_root(&data));
+        if self.last_job == Some(job) {
+            return false;
+        }
+        self.last_job = Some(job);
+        let block = block_template(data, chain, node_name);
+        self.jobs.start(block, &chain.params.chain_id, tx_mpsc);
+        true
+    }
+
+    /// Lets the last block be started again, as after the chain rejected it.
+    pub fn forget_job(&mut self) {
+        self.last_job = None;
+    }
+
+    /// Asks every worker to abandon the current block and waits for them,
+    /// so a new block can be started right away.
+    pub fn stop(&mut self) {
+        self.last_job = None;
+        self.jobs.stop();
+    }
+}
+
+/// Unmined block carrying `data` on top of the active chain.
+///
+/// The timestamp is the time of the chain's clock, but never at or below
+/// the median time of the previous blocks, so a node with a lagging clock
+/// still produces valid blocks.
+pub fn block_template(data: Vec<BlockData>, chain: &ChainState, node_name: &str) -> Block {
+    let blocks = chain.blocks();
+    let tip = chain.tip();
+    let now = chain.clock().now();
+    let timestamp = match median_time_past(blocks) {
+        Some(s) => now.max(s + 1),
+        None => now,
+    };
+
+    Block {
+        hash: [0; HASH_LEN],
+        id: tip.id + 1,
+        nonce: 0,
+        prev_hash: tip.hash,
+        bits: next_bits(blocks, &chain.params),
+        timestamp,
+        extra_nonce: 0,
+        merkle_root: merkle_root(&data),
+        data,
+        mined_by: node_name.to_string(),
+    }
+}
+
+pub fn mint_block(
+    mut new_block: Block,
+    chain_id: &str,
+    threads: usize,
+    tx: Sender<Msg>,
+    rx: Receiver<Msg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    mine_block(&mut new_block, threads, &rx)?;
+
+    // The main thread sends the block on to the network once it is accepted.
+    tx.send(Msg::new(chain_id, Comm::NewBlock, serialize(&new_block)?))?;
+    Ok(())
+}
+
+/// Mines `new_block` in place, moving on to the next `extra_nonce` every
+/// time the whole `u32` nonce space has been searched.
+pub(crate) fn mine_block(
+    new_block: &mut Block,
+    threads: usize,
+    rx: &Receiver<Msg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    mine_with_limit(new_block, threads, u32::MAX, rx)
+}
+
+fn mine_with_limit(
+    new_block: &mut Block,
+    threads: usize,
+    last_nonce: u32,
+    rx: &Receiver<Msg>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        if let Some((nonce, hash)) = search_nonces(new_block, threads, last_nonce, rx)? {
+            new_block.nonce = nonce;
+            new_block.hash = hash;
+            return Ok(());
+        }
+        debug!(
+            "Nonce space exhausted for extra nonce {}",
+            new_block.extra_nonce
+        );
+        new_block.extra_nonce = match new_block.extra_nonce.checked_add(1) {
+            Some(s) => s,
+            None => {
+                ret_err!("Extra nonce space exhausted.");
+            }
+        };
+    }
+}
+
+/// Searches the nonces up to `last_nonce` with `threads` workers, worker `i`
+/// trying the nonces `i`, `i + threads`, `i + 2 * threads` and so on.
+fn search_nonces(
+    new_block: &Block,
+    threads: usize,
+    last_nonce: u32,
+    rx: &Receiver<Msg>,
+) -> Result<Option<Solution>, Box<dyn std::error::Error>> {
+    let bytes = header_preimage(new_block)?;
+    let threads = threads.max(1) as u32;
+    let stop = AtomicBool::new(false);
+    let hashes = AtomicU64::new(0);
+    let started = Instant::now();
+
+    let found = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|first| {
+                let (bytes, stop, hashes) = (&bytes, &stop, &hashes);
+                let range = NonceRange {
+                    first,
+                    last: last_nonce,
+                    stride: threads,
+                };
+                scope.spawn(move || mine_range(bytes, new_block.bits, range, stop, hashes, rx))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .filter_map(|worker| worker.join().unwrap_or(None))
+            .min_by_key(|(nonce, _)| *nonce)
+    });
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let hashes = hashes.load(Ordering::Relaxed);
+    info!(
+        "Mining ran {:.1}s with {} threads: {} hashes, {:.0} H/s",
+        elapsed,
+        threads,
+        hashes,
+        hashes as f64 / elapsed.max(f64::EPSILON)
+    );
+
+    if found.is_none() && !rx.is_empty() {
+        ret_err!("Mining stopped via message.");
+    }
+    Ok(found)
+}
+
+/// Nonces `first`, `first + stride`, ... not greater than `last`.
+#[derive(Clone, Copy)]
+struct NonceRange {
+    first: u32,
+    last: u32,
+    stride: u32,
+}
+
+fn mine_range(
+    bytes: &[u8],
+    bits: u32,
+    range: NonceRange,
+    stop: &AtomicBool,
+    hashes: &AtomicU64,
+    rx: &Receiver<Msg>,
+) -> Option<Solution> {
+    if range.first > range.last {
+        return None;
+    }
+    let mut nonce = range.first;
+    let mut done: u64 = 0;
+
+    loop {
+        if done == CHECK_INTERVAL {
+            hashes.fetch_add(done, Ordering::Relaxed);
+            done = 0;
+            if stop.load(Ordering::Relaxed) || !rx.is_empty() {
+                stop.store(true, Ordering::Relaxed);
+                return None;
+            }
+        }
+
+        let mut sha2_hash = Sha256::new();
+        sha2_hash.update(bytes);
+        sha2_hash.update(nonce.to_be_bytes());
+        let sum: [u8; HASH_LEN] = sha2_hash.finalize().into();
+        done += 1;
+
+        if hash_meets_target(&sum, bits) {
+            hashes.fetch_add(done, Ordering::Relaxed);
+            stop.store(true, Ordering::Relaxed);
+            return Some((nonce, sum));
+        }
+        nonce = match nonce.checked_add(range.stride) {
+            Some(s) if s <= range.last => s,
+            _ => {
+                hashes.fetch_add(done, Ordering::Relaxed);
+                return None;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mine_block, mine_with_limit, Miner};
+    use crate::difficulty::hash_meets_target;
+    use crate::test_utils::{easy_params, RecordedJobs};
+    use crate::{block_hash, Block, BlockData, Car, ChainState, Comm, Msg};
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn test_workers_find_valid_nonce() {
+        let mut block = Block::new_empty();
+        block.bits = 0x1fff_ff00;
+        let (_tx, rx) = unbounded::<Msg>();
+        mine_block(&mut block, 4, &rx).unwrap();
+        assert_eq!(block_hash(&block).unwrap(), block.hash);
+        assert!(block.hash[0] == 0);
+    }
+
+    #[test]
+    fn test_extra_nonce_extends_search() {
+        let mut block = Block::new_empty();
+        block.bits = 0x1fff_ff00;
+        let (_tx, rx) = unbounded::<Msg>();
+        // Only nonces 0 and 1 are available, so a solution needs extra nonces.
+        mine_with_limit(&mut block, 2, 1, &rx).unwrap();
+        assert!(block.extra_nonce > 0);
+        assert!(block.nonce <= 1);
+        assert_eq!(block_hash(&block).unwrap(), block.hash);
+        assert!(hash_meets_target(&block.hash, block.bits));
+
+        block.extra_nonce += 1;
+        assert_ne!(block_hash(&block).unwrap(), block.hash);
+    }
+
+    #[test]
+    fn test_end_mining_stops_workers() {
+        let mut block = Block::new_empty();
+        // A target nobody can hit, so only the message ends the search.
+        block.bits = 0x0100_0001;
+        let (tx, rx) = unbounded::<Msg>();
+        tx.send(Msg::new("", Comm::EndMining, Vec::new())).unwrap();
+        assert!(mine_block(&mut block, 2, &rx).is_err());
+    }
+
+    #[test]
+    fn test_same_block_not_mined_twice() {
+        let chain = ChainState::new(easy_params());
+        let entry =
+            |owner: &str| BlockData::Car(Car::new(Some(owner.to_string()), None, None, None));
+        let (tx, _rx) = unbounded::<Msg>();
+        let jobs = RecordedJobs::default();
+        let mut miner = Miner::new(1).with_jobs(Box::new(jobs.clone()));
+        assert!(miner.start(vec![entry("James")], &chain, "node", &tx));
+        assert!(jobs.take().is_some());
+
+        // The block is done but not added yet, the tip is still the same.
+        assert!(!miner.start(vec![entry("James")], &chain, "node", &tx));
+        assert!(miner.start(vec![entry("James"), entry("Oliver")], &chain, "node", &tx));
+        jobs.take();
+        miner.stop();
+        assert!(miner.start(vec![entry("James"), entry("Oliver")], &chain, "node", &tx));
+        jobs.take();
+        miner.forget_job();
+        assert!(miner.start(vec![entry("James"), entry("Oliver")], &chain, "node", &tx));
+    }
+
+    #[test]
+    fn test_failed_block_mined_again() {
+        let chain = ChainState::new(easy_params());
+        let data = vec![BlockData::Car(Car::new(None, None, None, None))];
+        // The main loop is gone, so the mined block can't be handed over.
+        let (tx, rx) = unbounded::<Msg>();
+        drop(rx);
+        let mut miner = Miner::new(1);
+        assert!(miner.start(data.clone(), &chain, "node", &tx));
+        while miner.is_running() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(miner.start(data, &chain, "node", &tx));
+        miner.stop();
+    }
+}
@@ -1,68 +1,1239 @@
-use crate::{Block, Comm, Msg};
+use crate::auth::ClusterKey;
+use crate::bans::{offence_of, BanList};
+use crate::config::NetConfig;
+use crate::datatypes::BlockchainError;
+use crate::framing::{encode_frames, Reassembler};
+use crate::{ret_err, unix_time, Comm, Msg, HASH_LEN};
 use bincode::{deserialize, serialize};
-use log::{debug, warn};
-use std::net::{Ipv4Addr, UdpSocket};
+use crossbeam_channel::{bounded, Receiver as CbReceiver, Sender as CbSender, TrySendError};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
 use std::str::FromStr;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::thread::JoinHandle;
-
-pub fn listen(tx: Sender<Msg>) {
-    let listener = UdpSocket::bind("0.0.0.0:9000").unwrap();
-    listener
-        .join_multicast_v4(
-            &Ipv4Addr::from_str("239.0.0.1").unwrap(),
-            &Ipv4Addr::UNSPECIFIED,
-        )
-        .expect("Error while joining multicast v4");
-    let mut threads: Vec<JoinHandle<()>> = Vec::new();
+use std::time::Duration;
+
+/// Size of the receive buffer, large enough for any UDP datagram.
+pub const MAX_DATAGRAM_LEN: usize = 65536;
+
+/// Peers not heard from for this many seconds are forgotten.
+pub const PEER_TIMEOUT: u64 = 120;
+
+/// Maximum number of peers kept in the table.
+const MAX_PEERS: usize = 128;
+
+/// Gossiped messages are recognised as duplicates for this many seconds.
+pub const SEEN_TIMEOUT: u64 = 600;
+
+/// Maximum number of message hashes kept in a `SeenCache`.
+const MAX_SEEN: usize = 4096;
+
+/// Maximum number of hosts `TrafficStats` keeps counters for.
+const MAX_SOURCES: usize = 1024;
+
+/// A received datagram and the address it came from.
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+/// Carries datagrams between nodes and clients.
+///
+/// Implementations only move bytes: framing, chain filtering and message
+/// handling are the same whatever the transport.
+pub trait Transport: Send + Sync {
+    /// Address other transports reach this one on.
+    fn local_addr(&self) -> SocketAddr;
+
+    /// Sends `datagram` to every node of the group.
+    fn send_group(&self, datagram: &[u8]) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Sends `datagram` to `addr` only.
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Waits for the next datagram, at most `timeout` if given. Returns
+    /// `None` on timeout.
+    fn recv(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Datagram>, Box<dyn std::error::Error>>;
+}
+
+fn recv_from_channel(
+    incoming: &Mutex<Receiver<Datagram>>,
+    timeout: Option<Duration>,
+) -> Result<Option<Datagram>, Box<dyn std::error::Error>> {
+    let incoming = match incoming.lock() {
+        Ok(s) => s,
+        Err(_) => {
+            ret_err!("Receiver lock is poisoned.");
+        }
+    };
+    let received = match timeout {
+        Some(s) => incoming.recv_timeout(s),
+        None => incoming.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+    match received {
+        Ok(s) => Ok(Some(s)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => {
+            ret_err!("Transport is closed.");
+        }
+    }
+}
+
+/// UDP socket that other sockets may bind to the same address as well, so
+/// several nodes on one host all receive the multicast group.
+fn reusable_socket(addr: SocketAddr) -> Result<UdpSocket, Box<dyn std::error::Error>> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Binds the sockets a node receives on: `config.listen`, joined to the
+/// multicast group, plus a socket on the group port if it differs.
+fn bind_listeners(config: &NetConfig) -> Result<Vec<UdpSocket>, Box<dyn std::error::Error>> {
+    let group = config.multicast_group;
+    let unicast = reusable_socket(config.listen)?;
+    if config.listen.port() == group.port() {
+        unicast.join_multicast_v4(group.ip(), &config.multicast_interface)?;
+        return Ok(vec![unicast]);
+    }
+    let multicast = reusable_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())))?;
+    multicast.join_multicast_v4(group.ip(), &config.multicast_interface)?;
+    Ok(vec![unicast, multicast])
+}
+
+/// Moves datagrams from `socket` into `tx`. While `tx` is full this waits, so
+/// the socket buffer fills up and the system drops datagrams.
+fn read_datagrams(socket: UdpSocket, tx: SyncSender<Datagram>) {
+    let mut frame: Vec<u8> = vec![0; MAX_DATAGRAM_LEN];
     loop {
-        let mut bytes: Vec<u8> = vec![0; 40960];
-        let (len, addr) = match listener.recv_from(&mut bytes) {
-            Ok(s) => s,
+        match socket.recv_from(&mut frame) {
+            Ok((len, addr)) => {
+                if tx.send((frame[..len].to_vec(), addr)).is_err() {
+                    return;
+                }
+            }
             Err(e) => {
                 warn!("Error in recv_from: {e}");
                 return;
             }
-        };
-        debug!("Remote connection from {:#?}, {} bytes read.", addr, len);
-
-        threads.push(thread::spawn({
-            let tx1 = tx.clone();
-            move || match handle_incoming(bytes, tx1) {
-                Ok(_) => {}
-                Err(e) => {
-                    warn!("Error while handling incoming message: {e}")
+        }
+    }
+}
+
+/// Datagrams over UDP, the group being the configured multicast group.
+pub struct UdpTransport {
+    /// Sends everything and receives unicast replies.
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    incoming: Mutex<Receiver<Datagram>>,
+}
+
+impl UdpTransport {
+    /// Transport of a node: receives on `config.listen` and the multicast
+    /// group, sends from `config.listen` so replies come back to it.
+    pub fn node(config: &NetConfig) -> Result<UdpTransport, Box<dyn std::error::Error>> {
+        UdpTransport::new(bind_listeners(config)?, config)
+    }
+
+    /// Transport of a client: sends from `config.bind` and receives the
+    /// replies there, without joining the group.
+    pub fn client(config: &NetConfig) -> Result<UdpTransport, Box<dyn std::error::Error>> {
+        UdpTransport::new(vec![UdpSocket::bind(config.bind)?], config)
+    }
+
+    fn new(
+        sockets: Vec<UdpSocket>,
+        config: &NetConfig,
+    ) -> Result<UdpTransport, Box<dyn std::error::Error>> {
+        let socket = sockets[0].try_clone()?;
+        socket.set_multicast_ttl_v4(config.multicast_ttl)?;
+        if !config.multicast_interface.is_unspecified() {
+            SockRef::from(&socket).set_multicast_if_v4(&config.multicast_interface)?;
+        }
+        let (tx, rx) = mpsc::sync_channel(config.queue_limit.max(1));
+        for receiver in sockets {
+            let tx = tx.clone();
+            thread::spawn(move || read_datagrams(receiver, tx));
+        }
+        Ok(UdpTransport {
+            socket,
+            group: config.multicast_group,
+            incoming: Mutex::new(rx),
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.socket
+            .local_addr()
+            .expect("Bound socket has a local address")
+    }
+
+    fn send_group(&self, datagram: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.socket.send_to(datagram, self.group)?;
+        Ok(())
+    }
+
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        self.socket.send_to(datagram, addr)?;
+        Ok(())
+    }
+
+    fn recv(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Datagram>, Box<dyn std::error::Error>> {
+        recv_from_channel(&self.incoming, timeout)
+    }
+}
+
+/// Transport joined to a `MemoryHub`.
+struct Member {
+    addr: SocketAddr,
+    /// Group sends only reach members of the same segment.
+    segment: u32,
+    tx: Sender<Datagram>,
+}
+
+/// Connects `MemoryTransport`s inside one process.
+#[derive(Clone, Default)]
+pub struct MemoryHub {
+    members: Arc<Mutex<Vec<Member>>>,
+}
+
+impl MemoryHub {
+    pub fn new() -> MemoryHub {
+        MemoryHub::default()
+    }
+
+    /// New transport on the hub. Addresses are handed out in order, the
+    /// first one being 10.0.0.1:9000.
+    pub fn join(&self) -> MemoryTransport {
+        self.join_segment(0)
+    }
+
+    /// New transport whose group sends only reach the members of `segment`,
+    /// like a node on a network multicast doesn't leave.
+    pub fn join_segment(&self, segment: u32) -> MemoryTransport {
+        let mut members = self.members.lock().unwrap();
+        let host = members.len() as u32 + 1;
+        let addr = SocketAddr::from((Ipv4Addr::from(0x0a00_0000 + host), 9000));
+        let (tx, rx) = mpsc::channel();
+        members.push(Member { addr, segment, tx });
+        MemoryTransport {
+            addr,
+            segment,
+            hub: self.clone(),
+            incoming: Mutex::new(rx),
+        }
+    }
+
+    fn deliver(&self, datagram: &[u8], from: &MemoryTransport, to: Option<SocketAddr>) {
+        let members = self.members.lock().unwrap();
+        for member in members.iter() {
+            let wanted = match to {
+                Some(s) => member.addr == s,
+                None => member.addr != from.addr && member.segment == from.segment,
+            };
+            if wanted {
+                // Members that went away just miss the datagram, like on a
+                // real network.
+                let _ = member.tx.send((datagram.to_vec(), from.addr));
+            }
+        }
+    }
+}
+
+/// In-memory transport for running several nodes in one process. Datagrams
+/// arrive in the order they were sent and are never lost, a group send
+/// reaches every other member of the same segment.
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    segment: u32,
+    hub: MemoryHub,
+    incoming: Mutex<Receiver<Datagram>>,
+}
+
+impl Transport for MemoryTransport {
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn send_group(&self, datagram: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.hub.deliver(datagram, self, None);
+        Ok(())
+    }
+
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        self.hub.deliver(datagram, self, Some(addr));
+        Ok(())
+    }
+
+    fn recv(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Datagram>, Box<dyn std::error::Error>> {
+        recv_from_channel(&self.incoming, timeout)
+    }
+}
+
+/// What `Inbox` does with a message that arrives while it is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Drops the message that just arrived.
+    DropNewest,
+    /// Drops the oldest queued message to make room for it.
+    DropOldest,
+    /// Stops receiving until the node takes a message. The queues behind it
+    /// and then the socket buffer fill up, and the system drops datagrams
+    /// instead.
+    Block,
+}
+
+impl FromStr for Overflow {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Overflow, BlockchainError> {
+        match s.to_lowercase().as_str() {
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "block" => Ok(Overflow::Block),
+            _ => Err(BlockchainError(format!(
+                "Unknown overflow policy {s}, expected drop-newest, drop-oldest or block."
+            ))),
+        }
+    }
+}
+
+/// Messages received from one host and how many of them were dropped
+/// because the inbox was full.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SourceStats {
+    pub addr: IpAddr,
+    pub received: u64,
+    pub dropped: u64,
+}
+
+/// Per host counters of the messages `listen` received.
+#[derive(Default)]
+pub struct TrafficStats {
+    sources: HashMap<IpAddr, SourceStats>,
+}
+
+impl TrafficStats {
+    pub fn new() -> TrafficStats {
+        TrafficStats::default()
+    }
+
+    /// Counters of every host, sorted by address.
+    pub fn sources(&self) -> Vec<SourceStats> {
+        let mut sources: Vec<SourceStats> = self.sources.values().cloned().collect();
+        sources.sort_by_key(|source| source.addr);
+        sources
+    }
+
+    pub fn get(&self, addr: IpAddr) -> Option<&SourceStats> {
+        self.sources.get(&addr)
+    }
+
+    /// Counters of `addr`, `None` once `MAX_SOURCES` hosts are tracked.
+    fn source(&mut self, addr: IpAddr) -> Option<&mut SourceStats> {
+        if !self.sources.contains_key(&addr) && self.sources.len() >= MAX_SOURCES {
+            return None;
+        }
+        Some(self.sources.entry(addr).or_insert(SourceStats {
+            addr,
+            received: 0,
+            dropped: 0,
+        }))
+    }
+
+    fn received(&mut self, addr: SocketAddr) {
+        if let Some(source) = self.source(addr.ip()) {
+            source.received += 1;
+        }
+    }
+
+    fn dropped(&mut self, addr: SocketAddr) {
+        if let Some(source) = self.source(addr.ip()) {
+            source.dropped += 1;
+            // Warn on the 1st, 2nd, 4th, 8th... drop, so a flood shows up in
+            // the log without flooding it.
+            if source.dropped.is_power_of_two() {
+                warn!(
+                    "Inbox full, dropped {} messages from {}",
+                    source.dropped, source.addr
+                );
+            }
+        }
+    }
+}
+
+/// Handles a reassembled message and the address it came from.
+type Handler = dyn Fn(Vec<u8>, SocketAddr) + Send + Sync;
+
+struct Job {
+    bytes: Vec<u8>,
+    addr: SocketAddr,
+}
+
+/// Fixed set of threads handling received messages from a bounded queue.
+pub struct WorkerPool {
+    queue: CbSender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue_limit: usize, handler: Arc<Handler>) -> WorkerPool {
+        let (queue, pending) = bounded::<Job>(queue_limit.max(1));
+        for _ in 0..workers.max(1) {
+            let jobs = pending.clone();
+            let handler = handler.clone();
+            thread::spawn(move || {
+                for job in jobs.iter() {
+                    handler(job.bytes, job.addr);
                 }
+            });
+        }
+        WorkerPool { queue }
+    }
+
+    /// Queues `bytes` received from `addr` for a worker, waiting while the
+    /// queue is full. Returns `false` if the workers are gone.
+    pub fn submit(&self, bytes: Vec<u8>, addr: SocketAddr) -> bool {
+        self.queue.send(Job { bytes, addr }).is_ok()
+    }
+}
+
+/// Bounded queue of received messages waiting for `handle_msg`. Messages
+/// arriving while it is full are handled according to its `Overflow` and
+/// counted against the host that sent them.
+#[derive(Clone)]
+pub struct Inbox {
+    queue: CbSender<Msg>,
+    pending: CbReceiver<Msg>,
+    overflow: Overflow,
+    traffic: Arc<Mutex<TrafficStats>>,
+}
+
+impl Inbox {
+    pub fn new(limit: usize, overflow: Overflow, traffic: Arc<Mutex<TrafficStats>>) -> Inbox {
+        let (queue, pending) = bounded::<Msg>(limit.max(1));
+        Inbox {
+            queue,
+            pending,
+            overflow,
+            traffic,
+        }
+    }
+
+    /// Where the queued messages come out, oldest first.
+    pub fn receiver(&self) -> &CbReceiver<Msg> {
+        &self.pending
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues `msg`. Returns `false` if it was dropped.
+    pub fn submit(&self, mut msg: Msg) -> bool {
+        loop {
+            if self.overflow == Overflow::Block {
+                return self.queue.send(msg).is_ok();
+            }
+            msg = match self.queue.try_send(msg) {
+                Ok(()) => return true,
+                Err(TrySendError::Full(s)) => s,
+                Err(TrySendError::Disconnected(_)) => return false,
+            };
+            if self.overflow == Overflow::DropNewest {
+                self.dropped(msg.origin);
+                return false;
+            }
+            if let Ok(oldest) = self.pending.try_recv() {
+                self.dropped(oldest.origin);
             }
-        }));
+        }
+    }
+
+    /// Counts a message received from `addr`.
+    fn received(&self, addr: SocketAddr) {
+        self.count(|traffic| traffic.received(addr));
+    }
+
+    fn dropped(&self, origin: Option<SocketAddr>) {
+        if let Some(addr) = origin {
+            self.count(|traffic| traffic.dropped(addr));
+        }
+    }
+
+    fn count(&self, update: impl FnOnce(&mut TrafficStats)) {
+        match self.traffic.lock() {
+            Ok(mut s) => update(&mut s),
+            Err(_) => warn!("Traffic counters lock is poisoned"),
+        }
     }
 }
 
-fn handle_incoming(bytes: Vec<u8>, tx: Sender<Msg>) -> Result<(), Box<dyn std::error::Error>> {
-    let msg = deserialize::<Msg>(&bytes)?;
+/// Receives messages of the chain `chain_id` on `transport` and hands them
+/// to a pool of `config.workers` threads, which decode them into `inbox`.
+/// Messages are counted per host in the inbox's traffic stats, hosts banned
+/// in `bans` are ignored and ones sending undecodable messages penalized.
+pub fn listen(
+    transport: Arc<dyn Transport>,
+    inbox: Inbox,
+    chain_id: String,
+    config: &NetConfig,
+    key: Option<Arc<ClusterKey>>,
+    bans: Arc<Mutex<BanList>>,
+) {
+    let mut reassembler = Reassembler::new();
+    let seen = Arc::new(Mutex::new(SeenCache::new()));
+    let handler = {
+        let bans = bans.clone();
+        let inbox = inbox.clone();
+        move |bytes: Vec<u8>, addr: SocketAddr| {
+            if let Err(e) = handle_incoming(bytes, addr, &inbox, &chain_id, key.as_deref(), &seen) {
+                warn!("Error while handling incoming message: {e}");
+                if let (Some(offence), Ok(mut bans)) = (offence_of(e.as_ref()), bans.lock()) {
+                    bans.penalize(addr.ip(), offence, unix_time());
+                }
+            }
+        }
+    };
+    let pool = WorkerPool::new(config.workers, config.queue_limit, Arc::new(handler));
+    loop {
+        let (frame, addr) = match transport.recv(None) {
+            Ok(Some(s)) => s,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Error receiving: {e}");
+                return;
+            }
+        };
+        debug!(
+            "Remote connection from {:#?}, {} bytes read.",
+            addr,
+            frame.len()
+        );
+        if bans
+            .lock()
+            .is_ok_and(|s| s.is_banned(addr.ip(), unix_time()))
+        {
+            debug!("Ignoring datagram from banned host {addr}");
+            continue;
+        }
+        let bytes = match reassembler.accept(addr, &frame) {
+            Ok(Some(s)) => s,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Rejecting message from {addr}: {e}");
+                continue;
+            }
+        };
+        inbox.received(addr);
+        if !pool.submit(bytes, addr) {
+            return;
+        }
+    }
+}
+
+fn handle_incoming(
+    bytes: Vec<u8>,
+    addr: SocketAddr,
+    inbox: &Inbox,
+    chain_id: &str,
+    key: Option<&ClusterKey>,
+    seen: &Mutex<SeenCache>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let msg = match decode_incoming(&bytes, addr, chain_id, key)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    let first = match seen.lock() {
+        Ok(mut s) => s.admit(&msg, unix_time()),
+        Err(_) => {
+            ret_err!("Seen cache lock is poisoned.");
+        }
+    };
+    if !first {
+        debug!("Dropping duplicate {:?} from {addr}", msg.command);
+        return Ok(());
+    }
     debug!("Received message: {:#?}", msg);
-    tx.send(msg)?;
+    let hash = is_gossip(&msg.command).then(|| message_hash(&msg));
+    if !inbox.submit(msg) {
+        // Not handled, so a resend must not be taken for a duplicate.
+        if let (Some(hash), Ok(mut seen)) = (hash, seen.lock()) {
+            seen.forget(&hash);
+        }
+    }
     Ok(())
 }
 
-pub fn send_all(msg: Msg) -> Result<(), Box<dyn std::error::Error>> {
-    let socket: UdpSocket = UdpSocket::bind("0.0.0.0:8000")?;
+/// Message in the reassembled `bytes` received from `addr`, `None` if it
+/// belongs to another chain. With a `key`, only messages sealed with it are
+/// accepted.
+pub(crate) fn decode_incoming(
+    bytes: &[u8],
+    addr: SocketAddr,
+    chain_id: &str,
+    key: Option<&ClusterKey>,
+) -> Result<Option<Msg>, Box<dyn std::error::Error>> {
+    let mut msg = match key {
+        Some(s) => deserialize::<Msg>(&s.open(bytes, unix_time())?)?,
+        None => deserialize::<Msg>(bytes)?,
+    };
+    msg.origin = Some(addr);
+    if msg.chain_id != chain_id {
+        debug!("Ignoring message for chain {}", msg.chain_id);
+        return Ok(None);
+    }
+    Ok(Some(msg))
+}
 
-    let bytes = socket.send_to(&serialize(&msg)?, "239.0.0.1:9000")?;
-    debug!("Broadcasted {} bytes", bytes);
+/// Sends messages over a `Transport`, split into as many frames as needed.
+#[derive(Clone)]
+pub struct Endpoint {
+    transport: Arc<dyn Transport>,
+    /// Seals sent messages and opens replies, if set.
+    key: Option<Arc<ClusterKey>>,
+}
 
-    Ok(())
+impl Endpoint {
+    pub fn new(transport: Arc<dyn Transport>) -> Endpoint {
+        Endpoint {
+            transport,
+            key: None,
+        }
+    }
+
+    /// Authenticates messages with `key`, if there is one.
+    pub fn with_key(mut self, key: Option<Arc<ClusterKey>>) -> Endpoint {
+        self.key = key;
+        self
+    }
+
+    pub fn transport(&self) -> &Arc<dyn Transport> {
+        &self.transport
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.transport.local_addr()
+    }
+
+    /// Serialized `msg`, sealed if there is a key.
+    fn encode(&self, msg: &Msg) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let bytes = serialize(msg)?;
+        match &self.key {
+            Some(s) => s.seal(&bytes, unix_time()),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Sends `msg` to the whole group.
+    pub fn send_all(&self, msg: &Msg) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sent = 0;
+        for frame in encode_frames(&self.encode(msg)?)? {
+            self.transport.send_group(&frame)?;
+            sent += frame.len();
+        }
+        debug!("Broadcasted {} bytes", sent);
+        Ok(())
+    }
+
+    /// Sends `msg` to a single node or client.
+    pub fn send_to(&self, msg: &Msg, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sent = 0;
+        for frame in encode_frames(&self.encode(msg)?)? {
+            self.transport.send_to(&frame, addr)?;
+            sent += frame.len();
+        }
+        debug!("Sent {} bytes to {}", sent, addr);
+        Ok(())
+    }
+
+    /// Sends `msg` to the group and to each of `peers`, so peers on networks
+    /// without multicast get it as well.
+    pub fn send_to_peers(
+        &self,
+        msg: &Msg,
+        peers: &PeerTable,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for addr in peers.addrs() {
+            if let Err(e) = self.send_to(msg, addr) {
+                debug!("Couldn't reach peer {addr}: {e}");
+            }
+        }
+        self.send_all(msg)
+    }
+
+    /// Sends a gossiped `msg` on like `send_to_peers`, but not back to the
+    /// peer it came `from`.
+    pub fn relay(
+        &self,
+        msg: &Msg,
+        peers: &PeerTable,
+        from: SocketAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for addr in peers.addrs().into_iter().filter(|addr| *addr != from) {
+            if let Err(e) = self.send_to(msg, addr) {
+                debug!("Couldn't reach peer {addr}: {e}");
+            }
+        }
+        self.send_all(msg)
+    }
+
+    /// Waits up to `timeout` for a message whose command matches `expected`
+    /// and returns it with the address it came from.
+    pub fn wait_for(
+        &self,
+        expected: fn(&Comm) -> bool,
+        timeout: Duration,
+    ) -> Result<Option<(Msg, SocketAddr)>, Box<dyn std::error::Error>> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut reassembler = Reassembler::new();
+        loop {
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            let (frame, addr) = match self.transport.recv(Some(left))? {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            let bytes = match reassembler.accept(addr, &frame) {
+                Ok(Some(s)) => s,
+                _ => continue,
+            };
+            let bytes = match &self.key {
+                Some(s) => match s.open(&bytes, unix_time()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        debug!("Ignoring reply from {addr}: {e}");
+                        continue;
+                    }
+                },
+                None => bytes,
+            };
+            match deserialize::<Msg>(&bytes) {
+                Ok(s) if expected(&s.command) => return Ok(Some((s, addr))),
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// Carried by `Ping` and `Pong`, tells the receiver how to reach the sender.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub name: String,
+    /// Port the sender listens on, replies to the source port may get lost.
+    pub listen_port: u16,
+}
+
+/// A node known to this one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    /// Address the peer listens on.
+    pub addr: SocketAddr,
+    /// Empty until the peer introduced itself.
+    pub name: String,
+    /// Unix time the peer was last heard from, 0 if never.
+    pub last_seen: u64,
+    /// Seeds come from configuration and are never forgotten.
+    pub seed: bool,
+}
+
+/// Nodes learned from multicast pings and the configured seeds, keyed by
+/// the address they listen on.
+///
+/// Peers are kept alive by answering pings; ones that stay silent for
+/// `PEER_TIMEOUT` seconds are dropped unless they are seeds, which keeps
+/// unicast working on networks without multicast.
+#[derive(Default)]
+pub struct PeerTable {
+    peers: HashMap<SocketAddr, PeerInfo>,
 }
 
-pub fn broadcast_chain(blockchain: &Vec<Block>) {
-    match send_all(Msg {
-        command: Comm::Blockchain,
-        data: serialize(blockchain).unwrap(),
-    }) {
-        Ok(_) => {}
-        Err(e) => {
-            warn!("Error during broadcasting chain: {e}");
+impl PeerTable {
+    pub fn new() -> PeerTable {
+        PeerTable::default()
+    }
+
+    /// Table holding only the given seeds.
+    pub fn with_seeds(seeds: &[SocketAddr]) -> PeerTable {
+        let mut table = PeerTable::new();
+        for addr in seeds {
+            table.add_seed(*addr);
+        }
+        table
+    }
+
+    /// Adds `addr` as a seed, which is never expired.
+    pub fn add_seed(&mut self, addr: SocketAddr) {
+        self.peers.insert(
+            addr,
+            PeerInfo {
+                addr,
+                name: String::new(),
+                last_seen: 0,
+                seed: true,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    /// Every known peer, ordered by address.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|peer| peer.addr);
+        peers
+    }
+
+    /// Addresses of every known peer.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.peers.keys().copied().collect()
+    }
+
+    /// Records that the node `name` listening on `addr` was heard from at
+    /// `now`. Returns `false` if the peer is new and the table is full.
+    pub fn seen(&mut self, addr: SocketAddr, name: &str, now: u64) -> bool {
+        if !self.peers.contains_key(&addr) {
+            if self.peers.len() >= MAX_PEERS {
+                return false;
+            }
+            info!("New peer {name} at {addr}");
         }
+        let peer = self.peers.entry(addr).or_insert_with(|| PeerInfo {
+            addr,
+            name: String::new(),
+            last_seen: 0,
+            seed: false,
+        });
+        peer.name = name.to_string();
+        peer.last_seen = now;
+        true
+    }
+
+    /// Drops peers that weren't heard from for `PEER_TIMEOUT` seconds before
+    /// `now`, except seeds. Returns their addresses.
+    pub fn expire(&mut self, now: u64) -> Vec<SocketAddr> {
+        let mut expired = Vec::new();
+        self.peers.retain(|addr, peer| {
+            let alive = peer.seed || now.saturating_sub(peer.last_seen) < PEER_TIMEOUT;
+            if !alive {
+                expired.push(*addr);
+            }
+            alive
+        });
+        expired
+    }
+}
+
+/// Whether `command` is relayed from node to node, and so may arrive several
+/// times over different paths.
+pub fn is_gossip(command: &Comm) -> bool {
+    matches!(command, Comm::NewBlock)
+}
+
+/// Hash identifying a message however many hops it took, the TTL and the
+/// origin are left out.
+pub fn message_hash(msg: &Msg) -> [u8; HASH_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(msg.chain_id.as_bytes());
+    hasher.update(serialize(&msg.command).unwrap_or_default());
+    hasher.update(&msg.data);
+    hasher.finalize().into()
+}
+
+/// Hashes of the gossiped messages received lately.
+#[derive(Default)]
+pub struct SeenCache {
+    seen: HashMap<[u8; HASH_LEN], u64>,
+    /// Hashes in the order they were first seen, oldest first.
+    order: VecDeque<[u8; HASH_LEN]>,
+}
+
+impl SeenCache {
+    pub fn new() -> SeenCache {
+        SeenCache::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Records `msg` as seen at `now`. Returns `false` if it is gossip seen
+    /// within the last `SEEN_TIMEOUT` seconds, every other message passes.
+    pub fn admit(&mut self, msg: &Msg, now: u64) -> bool {
+        if !is_gossip(&msg.command) {
+            return true;
+        }
+        while let Some(oldest) = self.order.front() {
+            let expired = self
+                .seen
+                .get(oldest)
+                .is_none_or(|seen| now.saturating_sub(*seen) >= SEEN_TIMEOUT);
+            if !expired && self.order.len() < MAX_SEEN {
+                break;
+            }
+            self.seen.remove(oldest);
+            self.order.pop_front();
+        }
+        let hash = message_hash(msg);
+        if self.seen.contains_key(&hash) {
+            return false;
+        }
+        self.seen.insert(hash, now);
+        self.order.push_back(hash);
+        true
+    }
+
+    /// Forgets the message with `hash`, so it is admitted again.
+    pub fn forget(&mut self, hash: &[u8; HASH_LEN]) {
+        self.seen.remove(hash);
+    }
+}
+
+/// Parses a comma separated list of `host:port` seed addresses, skipping
+/// entries that don't resolve.
+pub fn parse_seeds(list: &str) -> Vec<SocketAddr> {
+    let mut seeds = Vec::new();
+    for entry in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match entry.to_socket_addrs().map(|mut s| s.next()) {
+            Ok(Some(s)) => seeds.push(s),
+            _ => warn!("Ignoring seed {entry}: not a reachable host:port"),
+        }
+    }
+    seeds
+}
+
+/// Multicasts a `Ping` and sends one to every known peer, then forgets peers
+/// that have been silent for too long at `now`.
+pub fn ping_peers(
+    endpoint: &Endpoint,
+    chain_id: &str,
+    hello: &Hello,
+    peers: &mut PeerTable,
+    now: u64,
+) {
+    for addr in peers.expire(now) {
+        info!("Peer {addr} timed out");
+    }
+    let msg = Msg::new(chain_id, Comm::Ping, serialize(hello).unwrap());
+    if let Err(e) = endpoint.send_to_peers(&msg, peers) {
+        warn!("Error pinging peers: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_incoming, handle_incoming, listen, parse_seeds, Endpoint, Inbox, MemoryHub,
+        Overflow, PeerTable, SeenCache, TrafficStats, Transport, UdpTransport, PEER_TIMEOUT,
+        SEEN_TIMEOUT,
+    };
+    use crate::bans::offence_of;
+    use crate::sync::{read_message, write_message};
+    use crate::{unix_time, BanList, ClusterKey, Comm, Msg, NetConfig};
+    use bincode::serialize;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_message_larger_than_datagram_delivered() {
+        let config = NetConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            ..NetConfig::default()
+        };
+        let receiver = Endpoint::new(Arc::new(UdpTransport::client(&config).unwrap()));
+        let sender = Endpoint::new(Arc::new(UdpTransport::client(&config).unwrap()));
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 256) as u8).collect();
+        let msg = Msg::new("car-ledger-test", Comm::Blocks, data.clone());
+        sender.send_to(&msg, receiver.local_addr()).unwrap();
+
+        let (received, addr) = receiver
+            .wait_for(|s| matches!(s, Comm::Blocks), Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(addr, sender.local_addr());
+        assert_eq!(received.chain_id, "car-ledger-test");
+        assert_eq!(received.data, data);
+    }
+
+    #[test]
+    fn test_memory_transports_exchange_messages() {
+        let hub = MemoryHub::new();
+        let nodes: Vec<Endpoint> = (0..3)
+            .map(|_| Endpoint::new(Arc::new(hub.join())))
+            .collect();
+        let config = NetConfig::default();
+        let traffic = Arc::new(Mutex::new(TrafficStats::new()));
+        let inbox = Inbox::new(config.queue_limit, config.overflow, traffic);
+        for (i, node) in nodes.iter().enumerate().skip(1) {
+            let transport = node.transport().clone();
+            let inbox = inbox.clone();
+            let config = config.clone();
+            let chain_id = if i == 1 { "car-ledger-test" } else { "other" };
+            let bans = Arc::new(Mutex::new(BanList::new(60)));
+            thread::spawn(move || {
+                listen(transport, inbox, chain_id.to_string(), &config, None, bans)
+            });
+        }
+        let rx = inbox.receiver();
+
+        let data = vec![7; 20_000];
+        let msg = Msg::new("car-ledger-test", Comm::Blocks, data.clone());
+        nodes[0].send_all(&msg).unwrap();
+        nodes[0].send_to(&msg, nodes[2].local_addr()).unwrap();
+        nodes[2].send_to(&msg, nodes[1].local_addr()).unwrap();
+
+        let first = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let mut origins = vec![first.origin.unwrap(), second.origin.unwrap()];
+        origins.sort();
+        assert_eq!(origins, vec![nodes[0].local_addr(), nodes[2].local_addr()]);
+        assert_eq!(first.data, data);
+        // The third node is on another chain and drops everything.
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    fn from(addr: &str, data: u8) -> Msg {
+        Msg {
+            origin: Some(addr.parse().unwrap()),
+            ..Msg::new("car-ledger-test", Comm::GetPeers, vec![data])
+        }
+    }
+
+    /// Queues one message from a quiet host, then three from a noisy one
+    /// into an inbox holding two.
+    fn fill(overflow: Overflow) -> (Inbox, Arc<Mutex<TrafficStats>>, Vec<bool>) {
+        let traffic = Arc::new(Mutex::new(TrafficStats::new()));
+        let inbox = Inbox::new(2, overflow, traffic.clone());
+        assert!(inbox.submit(from("10.0.0.1:9000", 0)));
+        let queued = (1..4)
+            .map(|i| inbox.submit(from("10.0.0.2:9000", i)))
+            .collect();
+        (inbox, traffic, queued)
+    }
+
+    fn queued(inbox: &Inbox) -> Vec<u8> {
+        inbox.receiver().try_iter().map(|msg| msg.data[0]).collect()
+    }
+
+    fn dropped(traffic: &Mutex<TrafficStats>, host: &str) -> u64 {
+        let traffic = traffic.lock().unwrap();
+        traffic.get(host.parse().unwrap()).map_or(0, |s| s.dropped)
+    }
+
+    #[test]
+    fn test_full_inbox_drops_newest() {
+        let (inbox, traffic, submitted) = fill(Overflow::DropNewest);
+        assert_eq!(submitted, vec![true, false, false]);
+        assert_eq!(queued(&inbox), vec![0, 1]);
+        assert_eq!(dropped(&traffic, "10.0.0.1"), 0);
+        assert_eq!(dropped(&traffic, "10.0.0.2"), 2);
+    }
+
+    #[test]
+    fn test_full_inbox_drops_oldest() {
+        let (inbox, traffic, submitted) = fill(Overflow::DropOldest);
+        assert_eq!(submitted, vec![true, true, true]);
+        assert_eq!(queued(&inbox), vec![2, 3]);
+        assert_eq!(dropped(&traffic, "10.0.0.1"), 1);
+        assert_eq!(dropped(&traffic, "10.0.0.2"), 1);
+    }
+
+    #[test]
+    fn test_dropped_block_admitted_when_resent() {
+        let traffic = Arc::new(Mutex::new(TrafficStats::new()));
+        let inbox = Inbox::new(1, Overflow::DropNewest, traffic);
+        let seen = Mutex::new(SeenCache::new());
+        let addr: SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let block = serialize(&Msg::new("car-ledger-test", Comm::NewBlock, vec![1, 2, 3])).unwrap();
+        assert!(inbox.submit(from("10.0.0.1:9000", 0)));
+
+        handle_incoming(block.clone(), addr, &inbox, "car-ledger-test", None, &seen).unwrap();
+        assert_eq!(queued(&inbox), vec![0]);
+        assert!(seen.lock().unwrap().is_empty());
+
+        handle_incoming(block, addr, &inbox, "car-ledger-test", None, &seen).unwrap();
+        assert_eq!(queued(&inbox), vec![1]);
+    }
+
+    #[test]
+    fn test_flood_stays_within_queue_limit() {
+        let hub = MemoryHub::new();
+        let sender = Endpoint::new(Arc::new(hub.join()));
+        let node: Arc<dyn Transport> = Arc::new(hub.join());
+        let node_addr = node.local_addr();
+        let config = NetConfig {
+            queue_limit: 4,
+            ..NetConfig::default()
+        };
+        let traffic = Arc::new(Mutex::new(TrafficStats::new()));
+        let inbox = Inbox::new(config.queue_limit, config.overflow, traffic.clone());
+        thread::spawn({
+            let inbox = inbox.clone();
+            let bans = Arc::new(Mutex::new(BanList::new(60)));
+            move || {
+                listen(
+                    node,
+                    inbox,
+                    "car-ledger-test".to_string(),
+                    &config,
+                    None,
+                    bans,
+                )
+            }
+        });
+
+        // Nothing takes messages out of the inbox.
+        for i in 0..100 {
+            let msg = Msg::new("car-ledger-test", Comm::GetPeers, vec![i]);
+            sender.send_to(&msg, node_addr).unwrap();
+        }
+        let host = sender.local_addr().ip();
+        for _ in 0..500 {
+            let counted = traffic
+                .lock()
+                .unwrap()
+                .get(host)
+                .is_some_and(|s| s.received == 100 && s.dropped == 96);
+            if counted {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(dropped(&traffic, &host.to_string()), 96);
+        assert_eq!(inbox.len(), 4);
+    }
+
+    #[test]
+    fn test_silent_peers_expire_but_seeds_stay() {
+        let seed: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let mut table = PeerTable::with_seeds(&[seed]);
+        assert!(table.seen(peer, "node-2", 1_000));
+        assert!(table.seen(seed, "node-1", 1_050));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(&seed).unwrap().name, "node-1");
+
+        assert!(table.expire(1_000 + PEER_TIMEOUT - 1).is_empty());
+        assert_eq!(table.expire(1_050 + PEER_TIMEOUT), vec![peer]);
+        assert_eq!(table.expire(10_000), Vec::new());
+        assert_eq!(table.peers()[0].addr, seed);
+    }
+
+    #[test]
+    fn test_seen_cache_drops_repeated_gossip() {
+        let mut seen = SeenCache::new();
+        let block = Msg::new("car-ledger-test", Comm::NewBlock, vec![1, 2, 3]);
+        let relayed = Msg {
+            ttl: block.ttl - 1,
+            ..Msg::new("car-ledger-test", Comm::NewBlock, vec![1, 2, 3])
+        };
+        assert!(seen.admit(&block, 1000));
+        assert!(!seen.admit(&relayed, 1001));
+
+        // Only gossip is deduplicated, repeated queries are answered.
+        let query = Msg::new("car-ledger-test", Comm::GetPeers, Vec::new());
+        assert!(seen.admit(&query, 1002));
+        assert!(seen.admit(&query, 1003));
+        assert_eq!(seen.len(), 1);
+
+        assert!(seen.admit(&block, 1000 + SEEN_TIMEOUT));
+    }
+
+    #[test]
+    fn test_unauthenticated_messages_rejected() {
+        let key = ClusterKey::new(b"cluster secret");
+        let addr: SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let msg = Msg::new("car-ledger-test", Comm::Blocks, vec![1, 2, 3]);
+        let plain = serialize(&msg).unwrap();
+        let error = decode_incoming(&plain, addr, "car-ledger-test", Some(&key)).unwrap_err();
+        assert_eq!(offence_of(error.as_ref()), None);
+
+        let sealed = key.seal(&plain, unix_time()).unwrap();
+        let received = decode_incoming(&sealed, addr, "car-ledger-test", Some(&key))
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.data, vec![1, 2, 3]);
+        assert!(decode_incoming(&sealed, addr, "car-ledger-test", Some(&key)).is_err());
+    }
+
+    #[test]
+    fn test_sync_frame_not_replayed_as_datagram() {
+        let key = ClusterKey::new(b"cluster secret");
+        let addr: SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let msg = Msg::new("car-ledger-test", Comm::GetHeaders, vec![1, 2, 3]);
+        let mut frame = Vec::new();
+        write_message(&mut frame, &msg, Some(&key)).unwrap();
+        let received: Msg = read_message(&mut frame.as_slice(), Some(&key)).unwrap();
+        assert_eq!(received.data, vec![1, 2, 3]);
+
+        // The sealed payload after the length prefix is a valid datagram,
+        // so both channels have to share one replay cache.
+        assert!(decode_incoming(&frame[4..], addr, "car-ledger-test", Some(&key)).is_err());
+    }
+
+    #[test]
+    fn test_keyed_endpoint_ignores_unsealed_replies() {
+        let hub = MemoryHub::new();
+        let key = |secret: &[u8]| Some(Arc::new(ClusterKey::new(secret)));
+        let client = Endpoint::new(Arc::new(hub.join())).with_key(key(b"cluster secret"));
+        let stranger = Endpoint::new(Arc::new(hub.join()));
+        let node = Endpoint::new(Arc::new(hub.join())).with_key(key(b"cluster secret"));
+        let reply = Msg::new("car-ledger-test", Comm::Peers, Vec::new());
+        stranger.send_to(&reply, client.local_addr()).unwrap();
+        node.send_to(&reply, client.local_addr()).unwrap();
+
+        let (_, addr) = client
+            .wait_for(|s| matches!(s, Comm::Peers), Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(addr, node.local_addr());
+    }
+
+    #[test]
+    fn test_seed_list_parsing() {
+        let seeds = parse_seeds("10.0.0.1:9000, ,127.0.0.1:9100,not-an-address");
+        assert_eq!(
+            seeds,
+            vec![
+                "10.0.0.1:9000".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:9100".parse().unwrap()
+            ]
+        );
     }
 }
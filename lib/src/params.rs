@@ -0,0 +1,123 @@
+use crate::datatypes::{BlockchainError, HASH_LEN};
+use crate::merkle::merkle_root;
+use crate::{block_hash, ret_err, Block, BlockData};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+/// Consensus parameters of a chain.
+///
+/// All of them are embedded in the genesis block, so two nodes only agree on
+/// a genesis block if they agree on every parameter.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    /// Name carried by every message, traffic from other chains is ignored.
+    pub chain_id: String,
+    /// Timestamp of the genesis block.
+    pub genesis_timestamp: u64,
+    /// Compact target used for the first retarget period.
+    pub initial_bits: u32,
+    /// Easiest target a block is ever allowed to have.
+    pub pow_limit_bits: u32,
+    /// Desired number of seconds between two blocks.
+    pub target_block_time: u64,
+    /// Number of blocks between two difficulty adjustments.
+    pub retarget_interval: u32,
+    /// How many seconds a block timestamp may be ahead of the local clock.
+    pub max_future_drift: u64,
+}
+
+impl Default for ChainParams {
+    /// Parameters for the 10 replica deployment from `docker-compose.yml`.
+    fn default() -> ChainParams {
+        ChainParams {
+            chain_id: "car-ledger-main".to_string(),
+            genesis_timestamp: 1_672_531_200,
+            initial_bits: 0x1d81_0000,
+            pow_limit_bits: 0x1eff_ff00,
+            target_block_time: 30,
+            retarget_interval: 20,
+            max_future_drift: 2 * 60 * 60,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Parameters for small test networks: easy blocks, fast retargeting.
+    pub fn test() -> ChainParams {
+        ChainParams {
+            chain_id: "car-ledger-test".to_string(),
+            genesis_timestamp: 1_672_531_200,
+            initial_bits: 0x1eff_ff00,
+            pow_limit_bits: 0x1fff_ff00,
+            target_block_time: 5,
+            retarget_interval: 5,
+            max_future_drift: 10 * 60,
+        }
+    }
+
+    /// Picks a parameter set by name, `main` and `test` are recognised.
+    pub fn from_name(name: &str) -> Option<ChainParams> {
+        match name.to_lowercase().as_str() {
+            "main" => Some(ChainParams::default()),
+            "test" => Some(ChainParams::test()),
+            _ => None,
+        }
+    }
+
+    /// Reads the parameter set named by `BLOCKCHAIN_NETWORK`, `main` if unset,
+    /// with the chain ID optionally replaced by `BLOCKCHAIN_CHAIN_ID`.
+    pub fn from_env() -> Result<ChainParams, Box<dyn std::error::Error>> {
+        let name = env::var("BLOCKCHAIN_NETWORK").unwrap_or("main".to_string());
+        let mut params = match ChainParams::from_name(&name) {
+            Some(s) => s,
+            None => {
+                ret_err!(format!("Unknown network: {name}"));
+            }
+        };
+        if let Ok(chain_id) = env::var("BLOCKCHAIN_CHAIN_ID") {
+            params.chain_id = chain_id;
+        }
+        Ok(params)
+    }
+
+    /// The genesis block of the chain described by these parameters.
+    ///
+    /// It is built the same way on every node and is not mined, its only
+    /// entry is the parameter set itself.
+    pub fn genesis_block(&self) -> Block {
+        let data = vec![BlockData::Genesis(self.clone())];
+        let mut genesis = Block {
+            hash: [0; HASH_LEN],
+            id: 0,
+            prev_hash: [0; HASH_LEN],
+            nonce: 0,
+            bits: self.initial_bits,
+            timestamp: self.genesis_timestamp,
+            extra_nonce: 0,
+            merkle_root: merkle_root(&data),
+            data,
+            mined_by: String::new(),
+        };
+        genesis.hash = block_hash(&genesis).unwrap();
+        genesis
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChainParams;
+
+    #[test]
+    fn test_genesis_depends_on_params() {
+        let params = ChainParams::default();
+        assert_eq!(params.genesis_block(), params.genesis_block());
+
+        let mut other = params.clone();
+        other.chain_id = "car-ledger-other".to_string();
+        assert_ne!(params.genesis_block().hash, other.genesis_block().hash);
+        assert_ne!(
+            params.genesis_block().hash,
+            ChainParams::test().genesis_block().hash
+        );
+    }
+}
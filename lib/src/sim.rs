@@ -0,0 +1,714 @@
+//! Deterministic network simulator for multi-node tests.
+//!
+//! Every node runs `handle_msg` on its own `ChainState` and `NodeState`, but
+//! nothing happens in the background: datagrams, mining and sync downloads
+//! are events on a virtual clock, and every random choice comes from one
+//! seeded generator. The same seed always replays the same run.
+
+use crate::framing::Reassembler;
+use crate::miner::mine_block;
+use crate::networking::{decode_incoming, MemoryHub, MemoryTransport, SeenCache, Transport};
+use crate::sync::{answer, SyncSession};
+use crate::test_utils::{RecordedDownloads, RecordedJobs};
+use crate::{
+    handle_msg, Block, BlockData, ChainParams, ChainState, Clock, Comm, Miner, Msg, NetConfig,
+    NodeState, Syncer,
+};
+use bincode::serialize;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Runs longer than this many events are taken to never settle.
+const MAX_EVENTS: usize = 100_000;
+
+/// How the simulated network treats datagrams.
+#[derive(Clone, Debug)]
+pub struct NetConditions {
+    /// Delay every datagram has at least.
+    pub latency: Duration,
+    /// Random extra delay up to this much. Datagrams sent closer together
+    /// than the jitter may arrive out of order.
+    pub jitter: Duration,
+    /// Probability that a datagram is lost.
+    pub loss: f64,
+}
+
+impl Default for NetConditions {
+    fn default() -> NetConditions {
+        NetConditions {
+            latency: Duration::from_millis(50),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub net: NetConditions,
+    /// Time a node needs to mine a block, at least.
+    pub mining_time: Duration,
+    /// Random extra mining time up to this much.
+    pub mining_jitter: Duration,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            seed: 0,
+            net: NetConditions::default(),
+            mining_time: Duration::from_secs(1),
+            mining_jitter: Duration::ZERO,
+        }
+    }
+}
+
+/// SplitMix64, small and the same on every platform.
+struct SimRng(u64);
+
+impl SimRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..=max`.
+    fn up_to(&mut self, max: u64) -> u64 {
+        match max.checked_add(1) {
+            Some(s) => self.next_u64() % s,
+            None => self.next_u64(),
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+enum Event {
+    /// Datagram arriving at node `to`.
+    Datagram {
+        to: usize,
+        from: SocketAddr,
+        frame: Vec<u8>,
+    },
+    /// Message a node sends itself, like the miner or syncer threads of a
+    /// real node.
+    Local { to: usize, msg: Msg },
+    /// Mining job `job` of node `node` is done.
+    Mined { node: usize, job: u64 },
+    /// Sync request of node `node` arriving at node `peer`.
+    SyncRequest { node: usize, peer: usize, msg: Msg },
+    /// Answer to a sync request arriving back at node `node`.
+    SyncAnswer { node: usize, peer: usize, msg: Msg },
+}
+
+/// A simulated node.
+pub struct SimNode {
+    pub chain: ChainState,
+    pub state: NodeState,
+    transport: Arc<MemoryTransport>,
+    reassembler: Reassembler,
+    seen: SeenCache,
+    /// Blocks the miner of the node started, mined by the simulation.
+    jobs: RecordedJobs,
+    /// Downloads the syncer of the node started, run by the simulation.
+    downloads: RecordedDownloads,
+    tx: Sender<Msg>,
+    rx: Receiver<Msg>,
+    /// Id and block of the mining job the simulation is timing.
+    mining: Option<(u64, Block)>,
+    sync: Option<SyncSession>,
+}
+
+pub struct Simulation {
+    config: SimConfig,
+    rng: SimRng,
+    /// Virtual time in milliseconds.
+    now: u64,
+    /// Copy of `now` the clocks of the nodes read.
+    millis: Arc<AtomicU64>,
+    /// Pending events by time, then by the order they were scheduled in.
+    events: BTreeMap<(u64, u64), Event>,
+    next_event: u64,
+    next_job: u64,
+    nodes: Vec<SimNode>,
+    /// Partition group of every node, nodes only reach their own group.
+    groups: Vec<usize>,
+    abandoned_jobs: usize,
+    duplicates: usize,
+}
+
+impl Simulation {
+    /// `nodes` nodes on an empty chain, named `node0`, `node1` and so on,
+    /// all reached by multicast.
+    pub fn new(nodes: usize, params: &ChainParams, config: SimConfig) -> Simulation {
+        Simulation::with_segments(&vec![0; nodes], params, config)
+    }
+
+    /// One node per entry of `segments`, multicast from a node only reaches
+    /// the nodes of the same segment.
+    pub fn with_segments(segments: &[u32], params: &ChainParams, config: SimConfig) -> Simulation {
+        let hub = MemoryHub::new();
+        let millis = Arc::new(AtomicU64::new(0));
+        let clock = {
+            let millis = millis.clone();
+            let genesis = params.genesis_timestamp;
+            Clock::from_fn(move || genesis + millis.load(Ordering::SeqCst) / 1000)
+        };
+        let nodes = segments
+            .iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                let transport = Arc::new(hub.join_segment(*segment));
+                let jobs = RecordedJobs::default();
+                let downloads = RecordedDownloads::default();
+                let mut state = NodeState::new(
+                    &format!("node{i}"),
+                    Miner::new(1).with_jobs(Box::new(jobs.clone())),
+                    NetConfig::default(),
+                    transport.clone(),
+                );
+                state.syncer = Syncer::new().with_downloads(Box::new(downloads.clone()));
+                state.clock = clock.clone();
+                let (tx, rx) = unbounded();
+                SimNode {
+                    chain: ChainState::new(params.clone()).with_clock(clock.clone()),
+                    state,
+                    transport,
+                    reassembler: Reassembler::new(),
+                    seen: SeenCache::new(),
+                    jobs,
+                    downloads,
+                    tx,
+                    rx,
+                    mining: None,
+                    sync: None,
+                }
+            })
+            .collect::<Vec<SimNode>>();
+        Simulation {
+            rng: SimRng(config.seed),
+            config,
+            now: 0,
+            millis,
+            events: BTreeMap::new(),
+            next_event: 0,
+            next_job: 0,
+            groups: vec![0; nodes.len()],
+            nodes,
+            abandoned_jobs: 0,
+            duplicates: 0,
+        }
+    }
+
+    pub fn now(&self) -> Duration {
+        Duration::from_millis(self.now)
+    }
+
+    pub fn node(&self, i: usize) -> &SimNode {
+        &self.nodes[i]
+    }
+
+    pub fn nodes(&self) -> &[SimNode] {
+        &self.nodes
+    }
+
+    /// Mining jobs that were stopped before they finished.
+    pub fn abandoned_jobs(&self) -> usize {
+        self.abandoned_jobs
+    }
+
+    /// Gossiped messages dropped because a node had already seen them.
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    /// Makes nodes `a` and `b` seeds of each other, so they exchange gossip
+    /// by unicast whatever their segments.
+    pub fn connect(&mut self, a: usize, b: usize) {
+        let addr_a = self.nodes[a].transport.local_addr();
+        let addr_b = self.nodes[b].transport.local_addr();
+        self.nodes[a].state.peers.add_seed(addr_b);
+        self.nodes[b].state.peers.add_seed(addr_a);
+    }
+
+    /// Sends `msg` from node `from` to node `to` over the simulated network,
+    /// for tests that need a node to misbehave.
+    pub fn send(&mut self, from: usize, to: usize, msg: &Msg) {
+        let addr = self.nodes[to].transport.local_addr();
+        self.nodes[from]
+            .state
+            .endpoint
+            .send_to(msg, addr)
+            .expect("Memory transports don't fail");
+        self.collect_datagrams();
+    }
+
+    pub fn set_conditions(&mut self, net: NetConditions) {
+        self.config.net = net;
+    }
+
+    /// Hands `entry` to node `node`, as a client would.
+    pub fn submit(&mut self, node: usize, entry: &BlockData) {
+        let chain_id = self.nodes[node].chain.params.chain_id.clone();
+        let msg = Msg::new(&chain_id, Comm::DataToBlock, serialize(entry).unwrap());
+        self.schedule(0, Event::Local { to: node, msg });
+    }
+
+    /// Makes node `node` announce its tip and ping its peers, like the
+    /// periodic broadcast of a real node.
+    pub fn broadcast(&mut self, node: usize) {
+        let chain_id = self.nodes[node].chain.params.chain_id.clone();
+        let msg = Msg::new(&chain_id, Comm::Broadcast, Vec::new());
+        self.schedule(0, Event::Local { to: node, msg });
+    }
+
+    /// Splits the network, nodes only reach nodes of their own group. Nodes
+    /// not listed form one more group. Datagrams already on their way across
+    /// the split are lost.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        self.groups = vec![0; self.nodes.len()];
+        for (group, members) in groups.iter().enumerate() {
+            for node in members.iter() {
+                self.groups[*node] = group + 1;
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.groups = vec![0; self.nodes.len()];
+    }
+
+    /// Runs every event due within `duration`.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.now + duration.as_millis() as u64;
+        while let Some(entry) = self.events.first_entry() {
+            if entry.key().0 > end {
+                break;
+            }
+            let ((time, _), event) = entry.remove_entry();
+            self.set_now(time);
+            self.process(event);
+        }
+        self.set_now(end);
+    }
+
+    /// Runs until no event is left. Panics if the nodes never settle.
+    pub fn run_until_idle(&mut self) {
+        for _ in 0..MAX_EVENTS {
+            let ((time, _), event) = match self.events.pop_first() {
+                Some(s) => s,
+                None => return,
+            };
+            self.set_now(time);
+            self.process(event);
+        }
+        panic!("Simulation didn't settle within {MAX_EVENTS} events");
+    }
+
+    fn set_now(&mut self, now: u64) {
+        self.now = now;
+        self.millis.store(now, Ordering::SeqCst);
+    }
+
+    fn schedule(&mut self, delay: u64, event: Event) {
+        self.events
+            .insert((self.now + delay, self.next_event), event);
+        self.next_event += 1;
+    }
+
+    fn network_delay(&mut self) -> u64 {
+        let jitter = self.config.net.jitter.as_millis() as u64;
+        self.config.net.latency.as_millis() as u64 + self.rng.up_to(jitter)
+    }
+
+    fn connected(&self, a: usize, b: usize) -> bool {
+        self.groups[a] == self.groups[b]
+    }
+
+    fn index_of(&self, addr: SocketAddr) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.transport.local_addr().ip() == addr.ip())
+    }
+
+    /// Block timestamps follow the virtual clock, starting at genesis.
+    fn block_time(&self, node: usize) -> u64 {
+        self.nodes[node].chain.params.genesis_timestamp + self.now / 1000
+    }
+
+    fn process(&mut self, event: Event) {
+        match event {
+            Event::Datagram { to, from, frame } => {
+                let sender = match self.index_of(from) {
+                    Some(s) => s,
+                    None => return,
+                };
+                if !self.connected(sender, to) {
+                    return;
+                }
+                let now = self.block_time(to);
+                let node = &mut self.nodes[to];
+                if node.state.bans.lock().unwrap().is_banned(from.ip(), now) {
+                    return;
+                }
+                let bytes = match node.reassembler.accept(from, &frame) {
+                    Ok(Some(s)) => s,
+                    _ => return,
+                };
+                let chain_id = node.chain.params.chain_id.clone();
+                let msg = match decode_incoming(&bytes, from, &chain_id, None) {
+                    Ok(Some(s)) => s,
+                    _ => return,
+                };
+                if !node.seen.admit(&msg, now) {
+                    self.duplicates += 1;
+                    return;
+                }
+                self.deliver(to, msg);
+            }
+            Event::Local { to, msg } => self.deliver(to, msg),
+            Event::Mined { node, job } => self.finish_mining(node, job),
+            Event::SyncRequest { node, peer, msg } => {
+                if !self.connected(node, peer) {
+                    return self.end_sync(node);
+                }
+                match answer(&msg, &self.nodes[peer].chain) {
+                    Ok(msg) => {
+                        let delay = self.network_delay();
+                        self.schedule(delay, Event::SyncAnswer { node, peer, msg });
+                    }
+                    Err(_) => self.end_sync(node),
+                }
+            }
+            Event::SyncAnswer { node, peer, msg } => {
+                if !self.connected(node, peer) {
+                    return self.end_sync(node);
+                }
+                let session = match self.nodes[node].sync.as_mut() {
+                    Some(s) => s,
+                    None => return,
+                };
+                let blocks = match session.handle(&msg) {
+                    Ok(s) => s,
+                    Err(_) => return self.end_sync(node),
+                };
+                let next = session.next_request();
+                if !blocks.is_empty() {
+                    let chain_id = self.nodes[node].chain.params.chain_id.clone();
+                    let mut msg = Msg::new(&chain_id, Comm::Blocks, serialize(&blocks).unwrap());
+                    msg.origin = Some(self.nodes[peer].transport.local_addr());
+                    self.schedule(0, Event::Local { to: node, msg });
+                }
+                match next {
+                    Ok(Some(msg)) => {
+                        let delay = self.network_delay();
+                        self.schedule(delay, Event::SyncRequest { node, peer, msg });
+                    }
+                    _ => self.end_sync(node),
+                }
+            }
+        }
+    }
+
+    /// Runs `msg` through `handle_msg` of node `to` and schedules whatever
+    /// the node sent or started in response.
+    fn deliver(&mut self, to: usize, msg: Msg) {
+        let node = &mut self.nodes[to];
+        handle_msg(msg, &mut node.chain, &mut node.state, &node.tx);
+        self.collect_datagrams();
+        self.collect_local(to);
+        self.track_mining(to);
+        self.track_sync(to);
+    }
+
+    fn collect_datagrams(&mut self) {
+        for to in 0..self.nodes.len() {
+            while let Ok(Some((frame, from))) = self.nodes[to].transport.recv(Some(Duration::ZERO))
+            {
+                if self.rng.chance(self.config.net.loss) {
+                    continue;
+                }
+                let delay = self.network_delay();
+                self.schedule(delay, Event::Datagram { to, from, frame });
+            }
+        }
+    }
+
+    fn collect_local(&mut self, to: usize) {
+        let messages: Vec<Msg> = self.nodes[to].rx.try_iter().collect();
+        for msg in messages {
+            self.schedule(0, Event::Local { to, msg });
+        }
+    }
+
+    /// Times the mining job the node started, if it is a new one.
+    fn track_mining(&mut self, node: usize) {
+        let job = self.nodes[node].jobs.job();
+        let current = self.nodes[node].mining.as_ref().map(|(_, block)| block);
+        if job.as_ref() == current {
+            return;
+        }
+        if current.is_some() {
+            self.abandoned_jobs += 1;
+        }
+        self.nodes[node].mining = match job {
+            Some(block) => {
+                let id = self.next_job;
+                self.next_job += 1;
+                let jitter = self.config.mining_jitter.as_millis() as u64;
+                let delay = self.config.mining_time.as_millis() as u64 + self.rng.up_to(jitter);
+                self.schedule(delay, Event::Mined { node, job: id });
+                Some((id, block))
+            }
+            None => None,
+        };
+    }
+
+    fn finish_mining(&mut self, node: usize, job: u64) {
+        match &self.nodes[node].mining {
+            Some((id, _)) if *id == job => {}
+            _ => return,
+        }
+        let now = self.block_time(node);
+        let sim_node = &mut self.nodes[node];
+        sim_node.mining = None;
+        let mut block = match sim_node.jobs.take() {
+            Some(s) => s,
+            None => return,
+        };
+        block.timestamp = block.timestamp.max(now);
+        let (_tx, rx) = unbounded::<Msg>();
+        mine_block(&mut block, 1, &rx).expect("Mining can't be stopped");
+        let chain_id = sim_node.chain.params.chain_id.clone();
+        let msg = Msg::new(&chain_id, Comm::NewBlock, serialize(&block).unwrap());
+        self.schedule(0, Event::Local { to: node, msg });
+    }
+
+    /// Starts the download the node asked its syncer for, if it is a new one.
+    fn track_sync(&mut self, node: usize) {
+        if self.nodes[node].sync.is_some() {
+            return;
+        }
+        let request = match self.nodes[node].downloads.request() {
+            Some(s) => s,
+            None => return,
+        };
+        let peer = match self.index_of(request.peer) {
+            Some(s) => s,
+            None => return self.end_sync(node),
+        };
+        let clock = self.nodes[node].state.clock.clone();
+        let session = SyncSession::new(request.blocks, request.params).with_clock(clock);
+        match session.next_request() {
+            Ok(Some(msg)) => {
+                self.nodes[node].sync = Some(session);
+                let delay = self.network_delay();
+                self.schedule(delay, Event::SyncRequest { node, peer, msg });
+            }
+            _ => self.end_sync(node),
+        }
+    }
+
+    fn end_sync(&mut self, node: usize) {
+        self.nodes[node].sync = None;
+        self.nodes[node].downloads.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NetConditions, SimConfig, Simulation};
+    use crate::datatypes::GOSSIP_TTL;
+    use crate::test_utils::{easy_params, mine_on};
+    use crate::{BlockData, Car, Comm, Msg, NetConfig, HASH_LEN};
+    use bincode::serialize;
+    use std::time::Duration;
+
+    fn entry(owner: &str) -> BlockData {
+        BlockData::Car(Car::new(Some(owner.to_string()), None, None, None))
+    }
+
+    fn tips(sim: &Simulation) -> Vec<[u8; HASH_LEN]> {
+        sim.nodes()
+            .iter()
+            .map(|node| node.chain.tip().hash)
+            .collect()
+    }
+
+    fn converged(sim: &Simulation) -> bool {
+        let tips = tips(sim);
+        tips.iter().all(|tip| *tip == tips[0])
+    }
+
+    fn contains(sim: &Simulation, node: usize, entry: &BlockData) -> bool {
+        sim.node(node)
+            .chain
+            .blocks()
+            .iter()
+            .any(|block| block.data.contains(entry))
+    }
+
+    fn lossy_run(seed: u64) -> Vec<[u8; HASH_LEN]> {
+        let config = SimConfig {
+            seed,
+            net: NetConditions {
+                latency: Duration::from_millis(20),
+                jitter: Duration::from_millis(300),
+                loss: 0.1,
+            },
+            mining_jitter: Duration::from_millis(500),
+            ..SimConfig::default()
+        };
+        let mut sim = Simulation::new(4, &easy_params(), config);
+        for i in 0..8 {
+            sim.submit(i % 4, &entry(&format!("Owner {i}")));
+            sim.run_for(Duration::from_millis(700));
+        }
+        sim.run_until_idle();
+        tips(&sim)
+    }
+
+    #[test]
+    fn test_same_seed_replays_same_run() {
+        assert_eq!(lossy_run(7), lossy_run(7));
+    }
+
+    #[test]
+    fn test_simultaneous_blocks_fork_then_converge() {
+        let mut sim = Simulation::new(2, &easy_params(), SimConfig::default());
+        sim.submit(0, &entry("Alice"));
+        sim.submit(1, &entry("Bob"));
+        sim.run_for(Duration::from_secs(1) + Duration::from_millis(100));
+
+        // Both found block 1 at the same time and keep their own.
+        assert!(!converged(&sim));
+        assert_eq!(sim.node(0).chain.blocks().len(), 2);
+        let bob_block = sim.node(1).chain.tip().hash;
+        assert!(sim.node(0).chain.contains(&bob_block));
+
+        // The next block decides, the loser mines its entry again.
+        sim.submit(0, &entry("Carol"));
+        sim.run_until_idle();
+        assert!(converged(&sim));
+        for owner in ["Alice", "Bob", "Carol"] {
+            assert!(contains(&sim, 0, &entry(owner)), "{owner} lost");
+        }
+    }
+
+    #[test]
+    fn test_block_arriving_mid_mining_abandons_job() {
+        let mut sim = Simulation::new(2, &easy_params(), SimConfig::default());
+        sim.submit(0, &entry("Alice"));
+        sim.run_for(Duration::from_millis(500));
+        assert_eq!(sim.now(), Duration::from_millis(500));
+        sim.submit(1, &entry("Bob"));
+        // Node 0's block arrives at 1050 ms, before node 1 is done at 1500.
+        sim.run_until_idle();
+
+        assert_eq!(sim.abandoned_jobs(), 1);
+        assert!(converged(&sim));
+        let blocks = sim.node(1).chain.blocks();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[1].mined_by, "node0");
+        assert_eq!(blocks[2].mined_by, "node1");
+    }
+
+    #[test]
+    fn test_blocks_relayed_across_segments() {
+        let mut sim =
+            Simulation::with_segments(&[0, 0, 1, 1, 2], &easy_params(), SimConfig::default());
+        sim.connect(1, 2);
+        sim.connect(3, 4);
+        sim.submit(0, &entry("Alice"));
+        sim.run_until_idle();
+
+        assert!(converged(&sim));
+        assert_eq!(sim.node(4).chain.blocks().len(), 2);
+        // Relays multicast back into the segment the block came from.
+        assert!(sim.duplicates() > 0);
+    }
+
+    #[test]
+    fn test_relay_stops_when_ttl_runs_out() {
+        let segments: Vec<u32> = (0..GOSSIP_TTL as u32 + 3).collect();
+        let mut sim = Simulation::with_segments(&segments, &easy_params(), SimConfig::default());
+        for i in 1..segments.len() {
+            sim.connect(i - 1, i);
+        }
+        sim.submit(0, &entry("Alice"));
+        sim.run_until_idle();
+
+        let heights: Vec<usize> = sim
+            .nodes()
+            .iter()
+            .map(|node| node.chain.blocks().len())
+            .collect();
+        let last = segments.len() - 1;
+        assert!(heights[..last].iter().all(|height| *height == 2));
+        assert_eq!(heights[last], 1);
+    }
+
+    #[test]
+    fn test_node_sending_invalid_blocks_gets_banned() {
+        let params = easy_params();
+        let mut sim = Simulation::new(2, &params, SimConfig::default());
+        for i in 0..2 {
+            let mut block = mine_on(&[params.genesis_block()], &params, &format!("Mallory {i}"));
+            block.nonce ^= 1;
+            let msg = Msg::new(&params.chain_id, Comm::NewBlock, serialize(&block).unwrap());
+            sim.send(0, 1, &msg);
+        }
+        sim.run_until_idle();
+        let entries = sim.node(1).state.bans.lock().unwrap().entries();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].banned_until.is_some());
+
+        // Even valid blocks from the banned node are ignored now.
+        sim.submit(0, &entry("Mallory"));
+        sim.run_until_idle();
+        assert_eq!(sim.node(0).chain.blocks().len(), 2);
+        assert_eq!(sim.node(1).chain.blocks().len(), 1);
+
+        // The ban runs out on the virtual clock.
+        sim.run_for(Duration::from_secs(NetConfig::default().ban_time));
+        sim.broadcast(0);
+        sim.run_until_idle();
+        assert_eq!(sim.node(1).chain.blocks().len(), 2);
+    }
+
+    #[test]
+    fn test_partitioned_node_syncs_after_heal() {
+        let mut sim = Simulation::new(3, &easy_params(), SimConfig::default());
+        sim.partition(&[&[0], &[1, 2]]);
+        sim.submit(0, &entry("Alice"));
+        sim.submit(1, &entry("Bob"));
+        sim.run_until_idle();
+        sim.submit(2, &entry("Carol"));
+        sim.run_until_idle();
+        assert_eq!(sim.node(0).chain.blocks().len(), 2);
+        assert_eq!(sim.node(1).chain.blocks().len(), 3);
+
+        sim.heal();
+        sim.set_conditions(NetConditions {
+            latency: Duration::from_millis(200),
+            jitter: Duration::from_millis(100),
+            loss: 0.0,
+        });
+        sim.broadcast(1);
+        sim.run_until_idle();
+        assert!(converged(&sim));
+        for owner in ["Alice", "Bob", "Carol"] {
+            assert!(contains(&sim, 0, &entry(owner)), "{owner} lost");
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use crate::datatypes::{BlockchainError, HASH_LEN};
+use crate::{ret_err, Block};
+use bincode::{deserialize, serialize};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Bytes in front of every record: payload length and payload checksum.
+const RECORD_HEADER_LEN: usize = 8;
+
+/// Records larger than this can only come from a corrupted length field.
+const MAX_RECORD_LEN: usize = 1024 * 1024;
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let sum: [u8; HASH_LEN] = Sha256::digest(payload).into();
+    [sum[0], sum[1], sum[2], sum[3]]
+}
+
+/// Append-only file of blocks.
+///
+/// Each record is the big endian payload length, the first four bytes of the
+/// payload's SHA-256 and the bincode encoded block. Every append is flushed
+/// to disk before it returns. A record cut short by a crash, or one whose
+/// checksum doesn't match, ends the log: it is truncated away on the next
+/// open together with everything behind it.
+pub struct BlockLog {
+    file: File,
+    /// Offset of the record holding each stored block.
+    index: HashMap<[u8; HASH_LEN], u64>,
+    /// Offset right behind the last valid record.
+    end: u64,
+}
+
+impl BlockLog {
+    /// Opens or creates the log at `path` and returns it together with every
+    /// block it holds, in the order they were appended.
+    ///
+    /// The log stays locked while it is open, so a client can't import into
+    /// the log of a running node and interleave its records with the node's.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<(BlockLog, Vec<Block>), Box<dyn std::error::Error>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.try_lock().is_err() {
+            ret_err!("Block log is in use, is a node running on it?");
+        }
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut index = HashMap::new();
+        let mut blocks = Vec::new();
+        let mut end = 0;
+        while end < bytes.len() {
+            match read_record(&bytes[end..]) {
+                Some((block, len)) => {
+                    index.insert(block.hash, end as u64);
+                    blocks.push(block);
+                    end += len;
+                }
+                None => break,
+            }
+        }
+
+        if end < bytes.len() {
+            warn!(
+                "Truncating {} bytes of damaged records from the block log",
+                bytes.len() - end
+            );
+            file.set_len(end as u64)?;
+            file.sync_all()?;
+        }
+        let log = BlockLog {
+            file,
+            index,
+            end: end as u64,
+        };
+        Ok((log, blocks))
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, hash: &[u8; HASH_LEN]) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// Writes `block` to the end of the log and waits until it is on disk.
+    /// Blocks already in the log are skipped.
+    pub fn append(&mut self, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        if self.contains(&block.hash) {
+            return Ok(());
+        }
+        let payload = serialize(block)?;
+        if payload.len() > MAX_RECORD_LEN {
+            ret_err!("Block is too large for the block log.");
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend((payload.len() as u32).to_be_bytes());
+        record.extend(checksum(&payload));
+        record.extend(&payload);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        if let Err(e) = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data())
+        {
+            // Don't leave half a record behind for the next append to follow.
+            self.file.set_len(self.end)?;
+            return Err(Box::new(e));
+        }
+        self.index.insert(block.hash, self.end);
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    /// Reads the block with `hash` back from disk.
+    pub fn read(
+        &mut self,
+        hash: &[u8; HASH_LEN],
+    ) -> Result<Option<Block>, Box<dyn std::error::Error>> {
+        let offset = match self.index.get(hash) {
+            Some(s) => *s,
+            None => return Ok(None),
+        };
+        let mut header = [0; RECORD_HEADER_LEN];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut header)?;
+        let len = u32::from_be_bytes(header[..4].try_into()?) as usize;
+        if len > MAX_RECORD_LEN {
+            ret_err!("Block log record is too large.");
+        }
+        let mut payload = vec![0; len];
+        self.file.read_exact(&mut payload)?;
+        if checksum(&payload) != header[4..] {
+            ret_err!("Block log record checksum doesn't match.");
+        }
+        Ok(Some(deserialize(&payload)?))
+    }
+
+    /// Removes the record of the block with `hash` and every record appended
+    /// after it.
+    pub fn truncate_from(
+        &mut self,
+        hash: &[u8; HASH_LEN],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let offset = match self.index.get(hash) {
+            Some(s) => *s,
+            None => {
+                ret_err!("Block isn't stored in the block log.");
+            }
+        };
+        self.file.set_len(offset)?;
+        self.file.sync_all()?;
+        self.index.retain(|_, record| *record < offset);
+        self.end = offset;
+        Ok(())
+    }
+}
+
+/// Storage of the active chain, from the genesis block up to the tip.
+///
+/// Implementations only check that blocks are appended in order, validating
+/// them is left to the consensus code.
+pub trait ChainStore: Send + Sync {
+    /// Every stored block, lowest height first.
+    fn blocks(&self) -> &[Block];
+
+    fn get_by_hash(&self, hash: &[u8; HASH_LEN]) -> Option<&Block>;
+
+    /// Stores `block` on top of the current tip.
+    fn append(&mut self, block: Block) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Removes every block above `height` and returns them, lowest height
+    /// first.
+    fn rollback(&mut self, height: u32) -> Result<Vec<Block>, Box<dyn std::error::Error>>;
+
+    fn len(&self) -> usize {
+        self.blocks().len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocks().is_empty()
+    }
+
+    fn get_by_height(&self, height: u32) -> Option<&Block> {
+        self.blocks().get(height as usize)
+    }
+
+    fn tip(&self) -> Option<&Block> {
+        self.blocks().last()
+    }
+}
+
+/// Chain kept in memory only, lost when the node stops.
+#[derive(Default)]
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+    /// Height of each stored block.
+    heights: HashMap<[u8; HASH_LEN], u32>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    fn get_by_hash(&self, hash: &[u8; HASH_LEN]) -> Option<&Block> {
+        self.heights
+            .get(hash)
+            .map(|height| &self.blocks[*height as usize])
+    }
+
+    fn append(&mut self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
+        if block.id as usize != self.blocks.len() {
+            ret_err!("Block height doesn't follow the stored tip.");
+        }
+        if let Some(tip) = self.blocks.last() {
+            if tip.hash != block.prev_hash {
+                ret_err!("Block doesn't extend the stored tip.");
+            }
+        }
+        self.heights.insert(block.hash, block.id);
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    fn rollback(&mut self, height: u32) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
+        let keep = (height as usize + 1).min(self.blocks.len());
+        let removed = self.blocks.split_off(keep);
+        for block in &removed {
+            self.heights.remove(&block.hash);
+        }
+        Ok(removed)
+    }
+}
+
+/// Chain kept in memory and mirrored to a `BlockLog`, so it survives
+/// restarts. Rolling back truncates the log.
+pub struct FileStore {
+    memory: MemoryStore,
+    log: BlockLog,
+}
+
+impl FileStore {
+    /// Opens the log at `path`. Stored blocks that don't extend the chain
+    /// before them are cut off together with everything behind them.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileStore, Box<dyn std::error::Error>> {
+        let (mut log, stored) = BlockLog::open(path)?;
+        let mut memory = MemoryStore::new();
+        for block in stored {
+            let hash = block.hash;
+            if let Err(e) = memory.append(block) {
+                warn!("Cutting off stored blocks that don't form a chain: {e}");
+                log.truncate_from(&hash)?;
+                break;
+            }
+        }
+        Ok(FileStore { memory, log })
+    }
+}
+
+impl ChainStore for FileStore {
+    fn blocks(&self) -> &[Block] {
+        self.memory.blocks()
+    }
+
+    fn get_by_hash(&self, hash: &[u8; HASH_LEN]) -> Option<&Block> {
+        self.memory.get_by_hash(hash)
+    }
+
+    fn append(&mut self, block: Block) -> Result<(), Box<dyn std::error::Error>> {
+        if block.id as usize != self.memory.len() {
+            ret_err!("Block height doesn't follow the stored tip.");
+        }
+        self.log.append(&block)?;
+        if let Err(e) = self.memory.append(block.clone()) {
+            self.log.truncate_from(&block.hash)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self, height: u32) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
+        if let Some(first) = self.memory.get_by_height(height + 1) {
+            self.log.truncate_from(&first.hash)?;
+        }
+        self.memory.rollback(height)
+    }
+}
+
+/// Decodes the record at the start of `bytes`, returning the block and the
+/// length of the whole record, or `None` if the record is incomplete or
+/// damaged.
+fn read_record(bytes: &[u8]) -> Option<(Block, usize)> {
+    let header = bytes.get(..RECORD_HEADER_LEN)?;
+    let len = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+    if len > MAX_RECORD_LEN {
+        return None;
+    }
+    let payload = bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+    if checksum(payload) != header[4..] {
+        return None;
+    }
+    let block = deserialize::<Block>(payload).ok()?;
+    Some((block, RECORD_HEADER_LEN + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockLog, ChainStore, FileStore, MemoryStore};
+    use crate::test_utils::{easy_params, mine_chain, mine_on};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_blocks_survive_reopen() {
+        let path = log_path("block-log-reopen");
+        let chain = mine_chain(4, &easy_params());
+
+        let (mut log, loaded) = BlockLog::open(&path).unwrap();
+        assert!(loaded.is_empty());
+        for block in &chain {
+            log.append(block).unwrap();
+        }
+        log.append(&chain[1]).unwrap();
+        assert_eq!(log.len(), chain.len());
+        assert_eq!(log.read(&chain[2].hash).unwrap().as_ref(), Some(&chain[2]));
+        drop(log);
+
+        let (mut log, loaded) = BlockLog::open(&path).unwrap();
+        assert_eq!(loaded, chain);
+        assert_eq!(log.read(&chain[3].hash).unwrap().as_ref(), Some(&chain[3]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_log_in_use_refused() {
+        let path = log_path("block-log-locked");
+        let (log, _) = BlockLog::open(&path).unwrap();
+        assert!(BlockLog::open(&path).is_err());
+        drop(log);
+        assert!(BlockLog::open(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_partial_record_truncated() {
+        let path = log_path("block-log-truncate");
+        let chain = mine_chain(3, &easy_params());
+
+        let (mut log, _) = BlockLog::open(&path).unwrap();
+        for block in &chain {
+            log.append(block).unwrap();
+        }
+        drop(log);
+
+        // Cut the last record in half, as a crash during the write would.
+        let full_len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 10).unwrap();
+        drop(file);
+
+        let (mut log, loaded) = BlockLog::open(&path).unwrap();
+        assert_eq!(loaded, chain[..2].to_vec());
+        assert!(std::fs::metadata(&path).unwrap().len() < full_len - 10);
+
+        log.append(&chain[2]).unwrap();
+        drop(log);
+        let (_, loaded) = BlockLog::open(&path).unwrap();
+        assert_eq!(loaded, chain);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_record_truncated() {
+        let path = log_path("block-log-corrupt");
+        let chain = mine_chain(3, &easy_params());
+
+        let (mut log, _) = BlockLog::open(&path).unwrap();
+        for block in &chain {
+            log.append(block).unwrap();
+        }
+        drop(log);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let (log, loaded) = BlockLog::open(&path).unwrap();
+        assert_eq!(loaded, chain[..2].to_vec());
+        assert!(!log.contains(&chain[2].hash));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_record_not_read() {
+        let path = log_path("block-log-oversized");
+        let chain = mine_chain(1, &easy_params());
+        let (mut log, _) = BlockLog::open(&path).unwrap();
+        log.append(&chain[0]).unwrap();
+
+        // A corrupted length field must not decide what gets allocated.
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        drop(file);
+
+        let error = log.read(&chain[0].hash).unwrap_err();
+        assert!(error.to_string().contains("too large"));
+        drop(log);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_memory_store_lookups_and_rollback() {
+        let params = easy_params();
+        let chain = mine_chain(4, &params);
+        let mut store = MemoryStore::new();
+        for block in &chain {
+            store.append(block.clone()).unwrap();
+        }
+        assert!(store.append(chain[2].clone()).is_err());
+        assert_eq!(store.tip(), chain.last());
+        assert_eq!(store.get_by_height(1), Some(&chain[1]));
+        assert_eq!(store.get_by_hash(&chain[2].hash), Some(&chain[2]));
+
+        assert_eq!(store.rollback(1).unwrap(), chain[2..].to_vec());
+        assert_eq!(store.blocks(), &chain[..2]);
+        assert!(store.get_by_hash(&chain[2].hash).is_none());
+        assert!(store.rollback(5).unwrap().is_empty());
+
+        let other = mine_on(&chain[..1], &params, "Other");
+        assert!(store.append(other).is_err());
+        store.append(chain[2].clone()).unwrap();
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn test_file_store_rollback_survives_reopen() {
+        let path = log_path("file-store-rollback");
+        let params = easy_params();
+        let chain = mine_chain(4, &params);
+        let mut fork = chain[..2].to_vec();
+        fork.push(mine_on(&fork, &params, "Fork"));
+
+        let mut store = FileStore::open(&path).unwrap();
+        for block in &chain {
+            store.append(block.clone()).unwrap();
+        }
+        assert_eq!(store.rollback(1).unwrap(), chain[2..].to_vec());
+        store.append(fork[2].clone()).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.blocks(), &fork[..]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,795 @@
+use crate::auth::ClusterKey;
+use crate::chain::{chain_work, locator};
+use crate::datatypes::{BlockHeader, BlockchainError, HASH_LEN};
+use crate::{
+    ret_err, unix_time, verify_entries, verify_header, Block, ChainParams, ChainState, Clock, Comm,
+    Msg, NodeState,
+};
+use bincode::{deserialize, serialize};
+use crossbeam_channel::Sender;
+use log::{debug, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Maximum number of headers in one response.
+pub const MAX_HEADERS: usize = 500;
+
+/// Maximum number of block bodies in one response.
+pub const MAX_BLOCKS: usize = 50;
+
+/// Largest sync message accepted from a peer.
+const MAX_SYNC_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// How long to wait on a peer before giving up on the connection.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of peers served at the same time, further connections
+/// are closed right away.
+const MAX_SYNC_PEERS: usize = 8;
+
+/// Seconds a peer isn't synced from again after its download failed or fell
+/// short of the work it announced.
+pub const SYNC_BACKOFF: u64 = 60;
+
+/// Tip of a node's active chain, multicast periodically so peers that are
+/// behind know whom to sync from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TipAnnouncement {
+    pub height: u32,
+    pub hash: [u8; HASH_LEN],
+    pub work: u128,
+    /// TCP port the announcing node serves sync requests on.
+    pub sync_port: u16,
+}
+
+/// Writes `message` prefixed with its length, sealed with `key` if set.
+pub fn write_message<T: Serialize>(
+    stream: &mut impl Write,
+    message: &T,
+    key: Option<&ClusterKey>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut bytes = serialize(message)?;
+    if let Some(s) = key {
+        bytes = s.seal(&bytes, unix_time())?;
+    }
+    if bytes.len() > MAX_SYNC_MESSAGE_LEN {
+        ret_err!("Sync message is too large.");
+    }
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads one length prefixed message, which has to be sealed with `key` if
+/// set.
+pub fn read_message<T: DeserializeOwned>(
+    stream: &mut impl Read,
+    key: Option<&ClusterKey>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_SYNC_MESSAGE_LEN {
+        ret_err!("Sync message is too large.");
+    }
+    // Only what actually arrives is allocated, not what the prefix claims.
+    let mut bytes = Vec::new();
+    stream.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        ret_err!("Sync message ended early.");
+    }
+    if let Some(s) = key {
+        bytes = s.open(&bytes, unix_time())?;
+    }
+    Ok(deserialize(&bytes)?)
+}
+
+/// Headers of the active chain following the first locator hash on it, or
+/// following genesis if none of them is.
+pub fn headers_after(chain: &ChainState, locator: &[[u8; HASH_LEN]]) -> Vec<BlockHeader> {
+    let store = chain.store();
+    let fork = locator
+        .iter()
+        .find_map(|hash| store.get_by_hash(hash))
+        .map_or(0, |block| block.id as usize);
+    store.blocks()[fork + 1..]
+        .iter()
+        .take(MAX_HEADERS)
+        .map(Block::header)
+        .collect()
+}
+
+/// Blocks known to `chain`, active or not, with the given hashes.
+pub fn blocks_by_hash(chain: &ChainState, hashes: &[[u8; HASH_LEN]]) -> Vec<Block> {
+    hashes
+        .iter()
+        .take(MAX_BLOCKS)
+        .filter_map(|hash| chain.get(hash).cloned())
+        .collect()
+}
+
+/// Answers a `GetHeaders` request with `Headers` and a `GetBlocks` request
+/// with `Blocks`.
+pub fn answer(request: &Msg, chain: &ChainState) -> Result<Msg, Box<dyn std::error::Error>> {
+    if request.chain_id != chain.params.chain_id {
+        ret_err!("Sync request is for another chain.");
+    }
+    let (command, data) = match request.command {
+        Comm::GetHeaders => {
+            let locator = deserialize::<Vec<[u8; HASH_LEN]>>(&request.data)?;
+            (Comm::Headers, serialize(&headers_after(chain, &locator))?)
+        }
+        Comm::GetBlocks => {
+            let hashes = deserialize::<Vec<[u8; HASH_LEN]>>(&request.data)?;
+            (Comm::Blocks, serialize(&blocks_by_hash(chain, &hashes))?)
+        }
+        _ => {
+            ret_err!("Message isn't a sync request.");
+        }
+    };
+    Ok(Msg::new(&chain.params.chain_id, command, data))
+}
+
+/// Answers sync requests arriving on `listener`, one thread per peer and at
+/// most `MAX_SYNC_PEERS` peers at a time. With a `key`, requests and answers
+/// are sealed like every other message.
+pub fn serve_sync(
+    listener: TcpListener,
+    chain: Arc<RwLock<ChainState>>,
+    key: Option<Arc<ClusterKey>>,
+) {
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Error accepting sync connection: {e}");
+                continue;
+            }
+        };
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_SYNC_PEERS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            debug!(
+                "Too many sync connections, closing {:?}",
+                stream.peer_addr()
+            );
+            continue;
+        }
+        let timeouts = stream
+            .set_read_timeout(Some(SYNC_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(SYNC_TIMEOUT)));
+        if let Err(e) = timeouts {
+            active.fetch_sub(1, Ordering::SeqCst);
+            warn!("Error setting sync connection timeouts: {e}");
+            continue;
+        }
+        let chain = chain.clone();
+        let active = active.clone();
+        let key = key.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_peer(stream, &chain, key.as_deref()) {
+                debug!("Sync connection closed: {e}");
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn serve_peer(
+    mut stream: TcpStream,
+    chain: &RwLock<ChainState>,
+    key: Option<&ClusterKey>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let request = read_message::<Msg>(&mut stream, key)?;
+        let response = match chain.read() {
+            Ok(s) => answer(&request, &s)?,
+            Err(_) => {
+                ret_err!("Chain lock is poisoned.");
+            }
+        };
+        write_message(&mut stream, &response, key)?;
+    }
+}
+
+/// Step of a headers first download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncState {
+    /// Waiting for the headers following the locator of the local view.
+    Headers,
+    /// Waiting for the bodies of validated headers.
+    Blocks,
+    /// The peer has nothing more to offer.
+    Done,
+}
+
+/// Headers first download from a single peer, independent of how messages
+/// travel.
+///
+/// `next_request` gives the message to send to the peer and `handle` takes
+/// its answer. Headers are validated against the chain they extend before
+/// any body is asked for, and each body has to match its header and pass the
+/// entry checks. `handle` returns the blocks that are ready to be added.
+pub struct SyncSession {
+    params: ChainParams,
+    /// Local chain followed by the headers validated so far, as blocks
+    /// without entries.
+    view: Vec<Block>,
+    /// Validated headers whose bodies haven't arrived yet, lowest first.
+    pending: VecDeque<BlockHeader>,
+    /// Whether the peer may have headers beyond the last batch.
+    more_headers: bool,
+    state: SyncState,
+    /// Time header timestamps are checked against.
+    clock: Clock,
+}
+
+impl SyncSession {
+    /// Session downloading what a peer has beyond `blocks`.
+    pub fn new(blocks: Vec<Block>, params: ChainParams) -> SyncSession {
+        SyncSession {
+            params,
+            view: blocks,
+            pending: VecDeque::new(),
+            more_headers: false,
+            state: SyncState::Headers,
+            clock: Clock::system(),
+        }
+    }
+
+    /// Checks header timestamps against `clock` instead of the wall clock.
+    pub fn with_clock(mut self, clock: Clock) -> SyncSession {
+        self.clock = clock;
+        self
+    }
+
+    pub fn state(&self) -> SyncState {
+        self.state
+    }
+
+    /// Work of the local chain joined with the headers validated so far.
+    pub fn work(&self) -> u128 {
+        chain_work(&self.view)
+    }
+
+    /// Message to send to the peer next, `None` once the download is done.
+    pub fn next_request(&self) -> Result<Option<Msg>, Box<dyn std::error::Error>> {
+        let (command, data) = match self.state {
+            SyncState::Headers => (Comm::GetHeaders, serialize(&locator(&self.view))?),
+            SyncState::Blocks => {
+                let hashes: Vec<[u8; HASH_LEN]> = self
+                    .pending
+                    .iter()
+                    .take(MAX_BLOCKS)
+                    .map(|header| header.hash)
+                    .collect();
+                (Comm::GetBlocks, serialize(&hashes)?)
+            }
+            SyncState::Done => return Ok(None),
+        };
+        Ok(Some(Msg::new(&self.params.chain_id, command, data)))
+    }
+
+    /// Processes the peer's answer to the last request.
+    pub fn handle(&mut self, msg: &Msg) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
+        if msg.chain_id != self.params.chain_id {
+            ret_err!("Sync answer is for another chain.");
+        }
+        match (self.state, &msg.command) {
+            (SyncState::Headers, Comm::Headers) => {
+                self.on_headers(deserialize(&msg.data)?)?;
+                Ok(Vec::new())
+            }
+            (SyncState::Blocks, Comm::Blocks) => self.on_blocks(deserialize(&msg.data)?),
+            _ => {
+                ret_err!(format!(
+                    "Unexpected {:?} while waiting for {:?}.",
+                    msg.command, self.state
+                ));
+            }
+        }
+    }
+
+    fn on_headers(&mut self, headers: Vec<BlockHeader>) -> Result<(), Box<dyn std::error::Error>> {
+        let fork = match headers.first() {
+            Some(s) => s.id as usize,
+            None => {
+                self.state = SyncState::Done;
+                return Ok(());
+            }
+        };
+        if fork == 0 || fork > self.view.len() {
+            ret_err!("Headers don't connect to the local chain.");
+        }
+        if headers.len() > MAX_HEADERS {
+            ret_err!("Peer sent too many headers.");
+        }
+        self.view.truncate(fork);
+        let now = self.clock.now();
+        for header in &headers {
+            let block = header.clone().into_block(Vec::new());
+            verify_header(&block, &self.view, &self.params, now)?;
+            self.view.push(block);
+        }
+        self.more_headers = headers.len() == MAX_HEADERS;
+        self.pending = headers.into();
+        self.state = SyncState::Blocks;
+        Ok(())
+    }
+
+    fn on_blocks(&mut self, blocks: Vec<Block>) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
+        if blocks.len() != self.pending.len().min(MAX_BLOCKS) {
+            ret_err!("Peer didn't send every requested block.");
+        }
+        let mut ready = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            let header = self.pending.pop_front().expect("Checked against count");
+            if block.header() != header {
+                ret_err!("Block doesn't match its header.");
+            }
+            ready.push(verify_entries(block)?);
+        }
+        if self.pending.is_empty() {
+            self.state = match self.more_headers {
+                true => SyncState::Headers,
+                false => SyncState::Done,
+            };
+        }
+        Ok(ready)
+    }
+}
+
+/// Result of a download that ran to the end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncReport {
+    /// Number of blocks downloaded.
+    pub downloaded: usize,
+    /// Work of the chain the downloaded blocks complete.
+    pub work: u128,
+}
+
+/// Runs a `SyncSession` against `peer` over TCP. Every batch of downloaded
+/// blocks is handed to `tx` as a `Blocks` message for the main thread to
+/// add, coming from `peer`. With a `key`, the session is sealed.
+pub fn sync_from(
+    peer: SocketAddr,
+    blocks: Vec<Block>,
+    params: &ChainParams,
+    tx: &Sender<Msg>,
+    key: Option<&ClusterKey>,
+) -> Result<SyncReport, Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect_timeout(&peer, SYNC_TIMEOUT)?;
+    stream.set_read_timeout(Some(SYNC_TIMEOUT))?;
+    let mut session = SyncSession::new(blocks, params.clone());
+    let mut downloaded = 0;
+    while let Some(request) = session.next_request()? {
+        write_message(&mut stream, &request, key)?;
+        let answer = read_message::<Msg>(&mut stream, key)?;
+        let blocks = session.handle(&answer)?;
+        if !blocks.is_empty() {
+            downloaded += blocks.len();
+            let mut msg = Msg::new(&params.chain_id, Comm::Blocks, serialize(&blocks)?);
+            msg.origin = Some(peer);
+            tx.send(msg)?;
+        }
+    }
+    Ok(SyncReport {
+        downloaded,
+        work: session.work(),
+    })
+}
+
+/// Sends a `GetHeaders` request with the locator of the active chain to the
+/// multicast group and to the peers of `node`. Peers that are ahead answer
+/// by announcing their tip.
+pub fn request_headers(
+    chain: &ChainState,
+    node: &NodeState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let locator = locator(chain.blocks());
+    let msg = Msg::new(
+        &chain.params.chain_id,
+        Comm::GetHeaders,
+        serialize(&locator)?,
+    );
+    node.endpoint.send_to_peers(&msg, &node.peers)
+}
+
+/// `Tip` message announcing the tip of the active chain.
+pub fn tip_announcement(
+    chain: &ChainState,
+    node: &NodeState,
+) -> Result<Msg, Box<dyn std::error::Error>> {
+    let tip = chain.tip();
+    let announcement = TipAnnouncement {
+        height: tip.id,
+        hash: tip.hash,
+        work: chain.work(),
+        sync_port: node.net.sync.port(),
+    };
+    Ok(Msg::new(
+        &chain.params.chain_id,
+        Comm::Tip,
+        serialize(&announcement)?,
+    ))
+}
+
+/// Sends the tip of the active chain to the multicast group and to the peers
+/// of `node`.
+pub fn announce_tip(
+    chain: &ChainState,
+    node: &NodeState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let msg = tip_announcement(chain, node)?;
+    node.endpoint.send_to_peers(&msg, &node.peers)
+}
+
+/// Download a `Syncer` starts.
+#[derive(Clone, Debug)]
+pub struct SyncRequest {
+    pub peer: SocketAddr,
+    /// Active chain of the node when the download was asked for.
+    pub blocks: Vec<Block>,
+    pub params: ChainParams,
+}
+
+/// Runs the downloads a `Syncer` starts.
+///
+/// Nodes download over TCP on a thread, the simulator runs the
+/// `SyncSession` over its virtual network instead.
+pub trait SyncDownloads: Send {
+    /// Starts `request`, handing the downloaded blocks to `tx`. With a
+    /// `key`, the session is sealed.
+    fn start(&mut self, request: SyncRequest, key: Option<Arc<ClusterKey>>, tx: &Sender<Msg>);
+
+    fn is_running(&self) -> bool;
+
+    /// Takes the outcome of the last download once it ended, `None` inside
+    /// if it failed.
+    fn finished(&mut self) -> Option<Option<SyncReport>>;
+}
+
+/// Runs every download with `sync_from` on a thread of its own.
+#[derive(Default)]
+pub struct SyncThread {
+    handle: Option<JoinHandle<Option<SyncReport>>>,
+}
+
+impl SyncDownloads for SyncThread {
+    fn start(&mut self, request: SyncRequest, key: Option<Arc<ClusterKey>>, tx: &Sender<Msg>) {
+        let tx = tx.clone();
+        self.handle = Some(thread::spawn(move || {
+            let SyncRequest {
+                peer,
+                blocks,
+                params,
+            } = request;
+            match sync_from(peer, blocks, &params, &tx, key.as_deref()) {
+                Ok(s) => {
+                    info!("Downloaded {} blocks from {peer}", s.downloaded);
+                    Some(s)
+                }
+                Err(e) => {
+                    warn!("Sync with {peer} failed: {e}");
+                    None
+                }
+            }
+        }));
+    }
+
+    fn is_running(&self) -> bool {
+        match &self.handle {
+            Some(s) => !s.is_finished(),
+            None => false,
+        }
+    }
+
+    fn finished(&mut self) -> Option<Option<SyncReport>> {
+        let handle = self.handle.take_if(|s| s.is_finished())?;
+        Some(handle.join().ok().flatten())
+    }
+}
+
+/// Runs at most one download from a peer in the background.
+///
+/// A peer whose download fails or falls short of the work it announced is
+/// backed off for `SYNC_BACKOFF` seconds, so a lying peer can't keep the
+/// syncer busy.
+pub struct Syncer {
+    downloads: Box<dyn SyncDownloads>,
+    /// Peer of the running download and the work it announced.
+    current: Option<(SocketAddr, u128)>,
+    /// End of the backoff of peers, by host.
+    backoff: HashMap<IpAddr, u64>,
+    /// Seals the sync sessions, if set.
+    key: Option<Arc<ClusterKey>>,
+}
+
+impl Default for Syncer {
+    fn default() -> Syncer {
+        Syncer {
+            downloads: Box::new(SyncThread::default()),
+            current: None,
+            backoff: HashMap::new(),
+            key: None,
+        }
+    }
+}
+
+impl Syncer {
+    pub fn new() -> Syncer {
+        Syncer::default()
+    }
+
+    /// Seals downloads with `key`, for peers that require it.
+    pub fn with_key(mut self, key: Option<Arc<ClusterKey>>) -> Syncer {
+        self.key = key;
+        self
+    }
+
+    /// Hands the downloads to `downloads` instead of a thread.
+    pub fn with_downloads(mut self, downloads: Box<dyn SyncDownloads>) -> Syncer {
+        self.downloads = downloads;
+        self
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.downloads.is_running()
+    }
+
+    pub fn is_backed_off(&self, peer: IpAddr, now: u64) -> bool {
+        self.backoff.get(&peer).is_some_and(|until| now < *until)
+    }
+
+    /// Starts downloading what `peer` has beyond the active chain, expecting
+    /// to reach the announced `work`. Returns `false` if a download is
+    /// already running or the peer is backed off.
+    pub fn start(
+        &mut self,
+        peer: SocketAddr,
+        work: u128,
+        chain: &ChainState,
+        tx: &Sender<Msg>,
+        now: u64,
+    ) -> bool {
+        if self.is_running() || self.is_backed_off(peer.ip(), now) {
+            return false;
+        }
+        let request = SyncRequest {
+            peer,
+            blocks: chain.blocks().to_vec(),
+            params: chain.params.clone(),
+        };
+        self.current = Some((peer, work));
+        self.downloads.start(request, self.key.clone(), tx);
+        true
+    }
+
+    /// Takes the outcome of a finished download. Its peer is backed off if
+    /// the download failed, and also returned if it delivered less work than
+    /// announced.
+    pub fn collect(&mut self, now: u64) -> Option<SocketAddr> {
+        let report = self.downloads.finished()?;
+        let (peer, work) = self.current.take()?;
+        match report {
+            Some(s) if s.work >= work => None,
+            Some(_) => {
+                self.back_off(peer.ip(), now);
+                Some(peer)
+            }
+            None => {
+                self.back_off(peer.ip(), now);
+                None
+            }
+        }
+    }
+
+    fn back_off(&mut self, peer: IpAddr, now: u64) {
+        self.backoff.retain(|_, until| now < *until);
+        self.backoff.insert(peer, now + SYNC_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        answer, read_message, serve_sync, sync_from, write_message, SyncSession, SyncState, Syncer,
+        MAX_SYNC_PEERS, SYNC_BACKOFF,
+    };
+    use crate::chain::chain_work;
+    use crate::test_utils::{add_all, easy_params, mine_chain, mine_on};
+    use crate::ClusterKey;
+    use crate::{Block, BlockHeader, ChainState, Comm, Msg};
+    use bincode::{deserialize, serialize};
+    use crossbeam_channel::{unbounded, Receiver};
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    fn serve(blocks: &[Block]) -> SocketAddr {
+        serve_sealed(blocks, None)
+    }
+
+    fn serve_sealed(blocks: &[Block], key: Option<Arc<ClusterKey>>) -> SocketAddr {
+        let mut chain = ChainState::new(easy_params());
+        add_all(&mut chain, blocks.to_vec());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_sync(listener, Arc::new(RwLock::new(chain)), key));
+        addr
+    }
+
+    fn received(rx: Receiver<Msg>) -> Vec<Block> {
+        rx.try_iter()
+            .flat_map(|msg| {
+                assert!(matches!(msg.command, Comm::Blocks));
+                deserialize::<Vec<Block>>(&msg.data).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_behind_node_catches_up() {
+        let params = easy_params();
+        let remote = mine_chain(8, &params);
+        let peer = serve(&remote);
+
+        let (tx, rx) = unbounded();
+        let report = sync_from(peer, remote[..3].to_vec(), &params, &tx, None).unwrap();
+        assert_eq!(report.downloaded, 5);
+        assert_eq!(report.work, chain_work(&remote));
+        assert_eq!(received(rx), remote[3..].to_vec());
+    }
+
+    #[test]
+    fn test_sync_sealed_with_cluster_key() {
+        let params = easy_params();
+        let remote = mine_chain(4, &params);
+        let key = || Some(Arc::new(ClusterKey::new(b"cluster secret")));
+        let peer = serve_sealed(&remote, key());
+
+        let (tx, rx) = unbounded();
+        assert!(sync_from(peer, remote[..2].to_vec(), &params, &tx, None).is_err());
+        let sealed = key();
+        let report = sync_from(peer, remote[..2].to_vec(), &params, &tx, sealed.as_deref());
+        assert_eq!(report.unwrap().downloaded, 2);
+        assert_eq!(received(rx), remote[2..].to_vec());
+    }
+
+    #[test]
+    fn test_diverged_node_downloads_from_fork_point() {
+        let params = easy_params();
+        let remote = mine_chain(8, &params);
+        let mut local = remote[..4].to_vec();
+        for _ in 0..2 {
+            let block = mine_on(&local, &params, "Local");
+            local.push(block);
+        }
+        let peer = serve(&remote);
+
+        let (tx, rx) = unbounded();
+        sync_from(peer, local.clone(), &params, &tx, None).unwrap();
+        let mut state = ChainState::new(params);
+        add_all(&mut state, local);
+        for block in received(rx) {
+            state.add_block(block).unwrap();
+        }
+        assert_eq!(state.blocks(), &remote[..]);
+    }
+
+    #[test]
+    fn test_tampered_header_rejected() {
+        let params = easy_params();
+        let remote = mine_chain(4, &params);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = listener.local_addr().unwrap();
+        let headers: Vec<BlockHeader> = remote[1..]
+            .iter()
+            .map(|block| {
+                let mut header = block.header();
+                if header.id == 2 {
+                    header.nonce ^= 1;
+                }
+                header
+            })
+            .collect();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_message::<Msg>(&mut stream, None).unwrap();
+            let headers = Msg::new(
+                &request.chain_id,
+                Comm::Headers,
+                serialize(&headers).unwrap(),
+            );
+            write_message(&mut stream, &headers, None).unwrap();
+        });
+
+        let (tx, rx) = unbounded();
+        assert!(sync_from(peer, remote[..1].to_vec(), &params, &tx, None).is_err());
+        assert!(received(rx).is_empty());
+    }
+
+    #[test]
+    fn test_session_fetches_headers_then_blocks() {
+        let params = easy_params();
+        let remote = mine_chain(6, &params);
+        let mut peer = ChainState::new(params.clone());
+        add_all(&mut peer, remote.clone());
+
+        let mut session = SyncSession::new(remote[..2].to_vec(), params);
+        let request = session.next_request().unwrap().unwrap();
+        assert!(matches!(request.command, Comm::GetHeaders));
+        assert!(session
+            .handle(&answer(&request, &peer).unwrap())
+            .unwrap()
+            .is_empty());
+        assert_eq!(session.state(), SyncState::Blocks);
+
+        let request = session.next_request().unwrap().unwrap();
+        assert!(matches!(request.command, Comm::GetBlocks));
+        let reply = answer(&request, &peer).unwrap();
+        assert_eq!(session.handle(&reply).unwrap(), remote[2..].to_vec());
+        assert_eq!(session.state(), SyncState::Done);
+        assert!(session.next_request().unwrap().is_none());
+
+        // Answers that don't fit the current step are rejected.
+        assert!(session.handle(&reply).is_err());
+    }
+    #[test]
+    fn test_peer_short_of_announced_work_backed_off() {
+        let params = easy_params();
+        let remote = mine_chain(6, &params);
+        let peer = serve(&remote[..4]);
+        let mut chain = ChainState::new(params);
+        add_all(&mut chain, remote[..2].to_vec());
+        let (tx, _rx) = unbounded();
+
+        let mut syncer = Syncer::new();
+        assert!(syncer.start(peer, chain_work(&remote), &chain, &tx, 1000));
+        while syncer.is_running() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(syncer.collect(1000), Some(peer));
+        assert!(!syncer.start(peer, chain_work(&remote), &chain, &tx, 1001));
+
+        // Once the backoff ran out, a truthful announcement is synced.
+        let work = chain_work(&remote[..4]);
+        assert!(syncer.start(peer, work, &chain, &tx, 1000 + SYNC_BACKOFF));
+        while syncer.is_running() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(syncer.collect(1000 + SYNC_BACKOFF), None);
+        assert!(!syncer.is_backed_off(peer.ip(), 1000 + SYNC_BACKOFF));
+    }
+
+    #[test]
+    fn test_truncated_message_rejected() {
+        let mut bytes = 1_000_000u32.to_be_bytes().to_vec();
+        bytes.extend([0; 10]);
+        assert!(read_message::<Msg>(&mut bytes.as_slice(), None).is_err());
+    }
+
+    #[test]
+    fn test_sync_connections_limited() {
+        let addr = serve(&[]);
+        let _served: Vec<TcpStream> = (0..MAX_SYNC_PEERS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let mut extra = TcpStream::connect(addr).unwrap();
+        extra
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(extra.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::difficulty::next_bits;
+use crate::merkle::merkle_root;
+use crate::miner::{mine_block, MiningJobs};
+use crate::sync::{SyncDownloads, SyncRequest};
+use crate::{Block, BlockData, Car, ChainParams, ChainState, ClusterKey, Msg, Reorg, SyncReport};
+use crossbeam_channel::{unbounded, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Parameters under which almost every hash is a valid proof of work.
+pub fn easy_params() -> ChainParams {
+    ChainParams {
+        initial_bits: 0x20ff_ff00,
+        pow_limit_bits: 0x20ff_ff00,
+        ..ChainParams::test()
+    }
+}
+
+/// Adds `blocks` to `chain`, failing the test if any of them is rejected.
+pub fn add_all(chain: &mut ChainState, blocks: Vec<Block>) -> Option<Reorg> {
+    let update = chain.add_chain(blocks);
+    if let Some(e) = update.error {
+        panic!("Blocks rejected: {e}");
+    }
+    update.reorg
+}
+
+/// Recomputes nonce and hash after a test modified a header field.
+pub fn remine(block: &mut Block) {
+    let (_tx, rx) = unbounded::<Msg>();
+    mine_block(block, 1, &rx).unwrap();
+}
+
+/// Mines a valid block on top of `chain` with the given timestamp.
+pub fn mine_at(chain: &[Block], params: &ChainParams, owner: &str, timestamp: u64) -> Block {
+    let mut block = Block::new_empty();
+    block.id = chain.len() as u32;
+    block.prev_hash = chain.last().unwrap().hash;
+    block.bits = next_bits(chain, params);
+    block.timestamp = timestamp;
+    block.data = vec![BlockData::Car(Car::new(
+        Some(owner.to_string()),
+        None,
+        None,
+        None,
+    ))];
+    block.merkle_root = merkle_root(&block.data);
+    remine(&mut block);
+    block
+}
+
+/// Mines a valid block on top of `chain`, spaced by the target block time.
+pub fn mine_on(chain: &[Block], params: &ChainParams, owner: &str) -> Block {
+    let timestamp = chain.last().unwrap().timestamp + params.target_block_time;
+    mine_at(chain, params, owner, timestamp)
+}
+
+/// Mining jobs that are only recorded, so the test decides when a block is
+/// mined.
+#[derive(Clone, Default)]
+pub struct RecordedJobs(Arc<Mutex<Option<Block>>>);
+
+impl RecordedJobs {
+    /// Block of the running job.
+    pub fn job(&self) -> Option<Block> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Ends the running job, handing its block over to the caller.
+    pub fn take(&self) -> Option<Block> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl MiningJobs for RecordedJobs {
+    fn start(&mut self, block: Block, _chain_id: &str, _tx: &Sender<Msg>) {
+        *self.0.lock().unwrap() = Some(block);
+    }
+
+    fn is_running(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    fn finished(&mut self) -> Option<bool> {
+        None
+    }
+
+    fn stop(&mut self) {
+        self.take();
+    }
+}
+
+/// Downloads that are only recorded, so the test decides how they run.
+#[derive(Clone, Default)]
+pub struct RecordedDownloads(Arc<Mutex<Option<SyncRequest>>>);
+
+impl RecordedDownloads {
+    /// Request of the running download.
+    pub fn request(&self) -> Option<SyncRequest> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Marks the running download as finished.
+    pub fn finish(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+impl SyncDownloads for RecordedDownloads {
+    fn start(&mut self, request: SyncRequest, _key: Option<Arc<ClusterKey>>, _tx: &Sender<Msg>) {
+        *self.0.lock().unwrap() = Some(request);
+    }
+
+    fn is_running(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    fn finished(&mut self) -> Option<Option<SyncReport>> {
+        None
+    }
+}
+
+/// Valid chain of `len` blocks, starting with the genesis block of `params`.
+pub fn mine_chain(len: usize, params: &ChainParams) -> Vec<Block> {
+    let mut chain = vec![params.genesis_block()];
+    for i in 1..len {
+        let block = mine_on(&chain, params, &format!("Owner {i}"));
+        chain.push(block);
+    }
+    chain
+}
@@ -0,0 +1,158 @@
+use crate::datatypes::Vin;
+use crate::{Block, BlockData, Car};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Current state of a vehicle and the blocks that recorded it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VehicleHistory {
+    pub vin: Vin,
+    /// Most recent record of the vehicle on the active chain.
+    pub current: Car,
+    /// Ids of every block holding a record of the vehicle, oldest first.
+    pub block_ids: Vec<u32>,
+}
+
+/// Records of every vehicle on the active chain, keyed by VIN.
+///
+/// Blocks have to be connected in chain order and disconnected in reverse
+/// chain order. Cars without a VIN aren't indexed. VINs are compared in
+/// upper case, however the record or the query spelled them.
+#[derive(Default)]
+pub struct VinIndex {
+    /// Every record of each vehicle with the id of its block, oldest first.
+    vehicles: HashMap<Vin, Vec<(u32, Car)>>,
+}
+
+fn cars(block: &Block) -> impl DoubleEndedIterator<Item = &Car> {
+    block.data.iter().filter_map(|entry| match entry {
+        BlockData::Car(s) if !s.vin().is_empty() => Some(s),
+        _ => None,
+    })
+}
+
+impl VinIndex {
+    pub fn new() -> VinIndex {
+        VinIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.vehicles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vehicles.is_empty()
+    }
+
+    pub fn get(&self, vin: &Vin) -> Option<VehicleHistory> {
+        let records = self.vehicles.get(&vin.to_canonical())?;
+        let (_, current) = records.last()?;
+        Some(VehicleHistory {
+            vin: vin.clone(),
+            current: current.clone(),
+            block_ids: records.iter().map(|(id, _)| *id).collect(),
+        })
+    }
+
+    /// Adds the cars of `block`, which extends the indexed chain.
+    pub fn connect(&mut self, block: &Block) {
+        for car in cars(block) {
+            self.vehicles
+                .entry(car.vin().to_canonical())
+                .or_default()
+                .push((block.id, car.clone()));
+        }
+    }
+
+    /// Removes the cars of `block`, the tip of the indexed chain.
+    pub fn disconnect(&mut self, block: &Block) {
+        for car in cars(block).rev() {
+            let vin = car.vin().to_canonical();
+            if let Some(records) = self.vehicles.get_mut(&vin) {
+                records.pop();
+                if records.is_empty() {
+                    self.vehicles.remove(&vin);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VinIndex;
+    use crate::datatypes::Vin;
+    use crate::{Block, BlockData, Car};
+
+    fn car(owner: &str, distance: u32, vin: &str) -> Car {
+        Car::new(
+            Some(owner.to_string()),
+            None,
+            Some(distance),
+            Vin::parse(vin),
+        )
+    }
+
+    fn block(id: u32, cars: &[Car]) -> Block {
+        let mut block = Block::new_empty();
+        block.id = id;
+        block.data = cars.iter().cloned().map(BlockData::Car).collect();
+        block
+    }
+
+    #[test]
+    fn test_history_follows_connect_and_disconnect() {
+        let vin = Vin::parse("1HGCM82633A004352").unwrap();
+        let other = Vin::parse("WVWZZZ1JZXW000001").unwrap();
+        let blocks = [
+            block(1, &[car("James", 100, "1HGCM82633A004352")]),
+            block(2, &[car("Oliver", 50, "WVWZZZ1JZXW000001"), car("", 0, "")]),
+            block(3, &[car("Max", 900, "1hgcm82633a004352")]),
+        ];
+
+        let mut index = VinIndex::new();
+        for block in &blocks {
+            index.connect(block);
+        }
+        assert_eq!(index.len(), 2);
+        let history = index.get(&vin).unwrap();
+        assert_eq!(history.current.owner_name(), "Max");
+        assert_eq!(history.current.distance_traveled(), 900);
+        assert_eq!(history.block_ids, vec![1, 3]);
+
+        index.disconnect(&blocks[2]);
+        let history = index.get(&vin).unwrap();
+        assert_eq!(history.current.owner_name(), "James");
+        assert_eq!(history.block_ids, vec![1]);
+
+        index.disconnect(&blocks[1]);
+        assert!(index.get(&other).is_none());
+        assert_eq!(index.len(), 1);
+    }
+
+    #[test]
+    fn test_lowercase_records_found() {
+        let lowercase = Vin::new(
+            Some("1hg".to_string()),
+            Some("cm8263".to_string()),
+            Some("3a004352".to_string()),
+        );
+        let record = Car::new(Some("James".to_string()), None, Some(100), Some(lowercase));
+        let recorded = block(1, std::slice::from_ref(&record));
+        let mut index = VinIndex::new();
+        index.connect(&recorded);
+
+        let vin = Vin::parse("1HGCM82633A004352").unwrap();
+        assert_eq!(index.get(&vin).unwrap().current, record);
+        index.disconnect(&recorded);
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_vin_parsing() {
+        let vin = Vin::parse("1hgcm82633a004352").unwrap();
+        assert_eq!(vin.to_string(), "1HGCM82633A004352");
+        assert!(Vin::parse("1HGCM82633A00435").is_none());
+        assert!(Vin::parse("1HGCM82633A00435!").is_none());
+    }
+}
@@ -1,14 +1,19 @@
 use chrono::Local;
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{select, unbounded};
 use env_logger::Builder;
 use gethostname::gethostname;
-use lib::datatypes::{Block, Msg};
-use lib::{handle_msg, networking::listen};
-use log::{debug, LevelFilter};
+use lib::datatypes::Msg;
+use lib::networking::{listen, ping_peers, Inbox, Transport, UdpTransport};
+use lib::sync::{request_headers, serve_sync};
+use lib::{handle_msg, ChainParams, ChainState, Miner, NetConfig, NodeState};
+use log::{debug, info, warn, LevelFilter};
+use std::env;
 use std::io::Write;
-use std::sync::mpsc;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
 use std::thread::sleep;
-use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
 fn main() {
@@ -25,8 +30,7 @@ fn main() {
         .filter(None, LevelFilter::Info)
         .init();
 
-    let (tx_mpsc, rx_mpsc) = mpsc::channel::<Msg>();
-    let (mut tx_mpmc, mut rx_mpmc) = unbounded::<Msg>();
+    let (tx_mpsc, rx_mpsc) = unbounded::<Msg>();
 
     let node_name = match gethostname().into_string() {
         Ok(s) => s,
@@ -35,48 +39,84 @@ fn main() {
         }
     };
 
-    let mut blocks: Vec<Block> = Vec::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (net, rest) = NetConfig::load(&args).expect("Invalid network configuration");
+    if !rest.is_empty() {
+        warn!("Ignoring arguments: {}", rest.join(" "));
+    }
+    let params = ChainParams::from_env().expect("Invalid chain parameters");
+    let chain_id = params.chain_id.clone();
+    info!("Starting node for chain {chain_id}");
+
+    let data_dir = env::var("BLOCKCHAIN_DATA_DIR").unwrap_or(".".to_string());
+    let log_path = Path::new(&data_dir).join(format!("{chain_id}.log"));
+    let chain = ChainState::open(params, &log_path).expect("Couldn't open the block log");
+    let chain = Arc::new(RwLock::new(chain));
+
+    let transport: Arc<dyn Transport> =
+        Arc::new(UdpTransport::node(&net).expect("Couldn't bind the listening sockets"));
+    info!("Listening on {} and {}", net.listen, net.multicast_group);
+
+    let sync_listener = TcpListener::bind(net.sync).expect("Couldn't bind the sync port");
+
+    let miner = match env::var("MINER_THREADS") {
+        Ok(s) => Miner::new(s.parse().expect("MINER_THREADS must be a number")),
+        Err(_) => Miner::with_all_cores(),
+    };
+    let mut node = NodeState::new(&node_name, miner, net, transport.clone());
 
-    let tx_mpsc_1 = tx_mpsc.clone();
+    thread::spawn({
+        let chain = chain.clone();
+        let key = node.key.clone();
+        move || serve_sync(sync_listener, chain, key)
+    });
 
+    // Messages from the network wait in a bounded queue, the node's own
+    // messages (mined blocks, downloads, timers) in `rx_mpsc`.
+    let inbox = Inbox::new(
+        node.net.queue_limit,
+        node.net.overflow,
+        node.traffic.clone(),
+    );
     thread::spawn({
+        let chain_id = chain_id.clone();
+        let net = node.net.clone();
+        let inbox = inbox.clone();
+        let key = node.key.clone();
+        let bans = node.bans.clone();
         move || {
-            listen(tx_mpsc_1);
+            listen(transport, inbox, chain_id, &net, key, bans);
         }
     });
 
+    let hello = node.hello();
+    let now = node.clock.now();
+    ping_peers(&node.endpoint, &chain_id, &hello, &mut node.peers, now);
+    // Peers that are ahead answer with their tip, so a node joining late
+    // doesn't wait for the next announcement.
+    if let Err(e) = request_headers(&chain.read().unwrap(), &node) {
+        warn!("Error asking peers for headers: {e}");
+    }
+
     let tx_mpsc_2 = tx_mpsc.clone();
 
+    // Announcements are small, so peers learn quickly that they are behind.
     thread::spawn({
         move || loop {
-            sleep(Duration::new(60, 0));
+            sleep(Duration::new(15, 0));
             tx_mpsc_2
-                .send(Msg {
-                    command: lib::Comm::Broadcast,
-                    data: Vec::new(),
-                })
+                .send(Msg::new(&chain_id, lib::Comm::Broadcast, Vec::new()))
                 .expect("Message to main thread couldn't be sent.");
         }
     });
 
-    let mut miner_thread: Option<JoinHandle<()>> = None;
-    let mut is_miner_running: bool = false;
-
-    for msg in rx_mpsc {
+    loop {
+        let msg = select! {
+            recv(rx_mpsc) -> msg => msg,
+            recv(inbox.receiver()) -> msg => msg,
+        };
+        let msg = msg.expect("Message channels are never closed.");
         debug!("Received msg: {:#?}", msg);
-        match msg.command {
-            _ => {
-                handle_msg(
-                    msg,
-                    &mut blocks,
-                    &mut is_miner_running,
-                    &mut miner_thread,
-                    &node_name,
-                    &tx_mpsc,
-                    &mut tx_mpmc,
-                    &mut rx_mpmc,
-                );
-            }
-        }
+        handle_msg(msg, &mut chain.write().unwrap(), &mut node, &tx_mpsc);
     }
 }